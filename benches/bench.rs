@@ -11,7 +11,6 @@ fn bench(c: &mut Criterion) {
     let value = Sentence(4000..4001);
 
     let insert_keys: Vec<String> = (0..10000)
-        .into_iter()
         .map(|_| {
             let k = key.fake::<String>();
             assert!(bitcask.put(k.clone(), value.fake::<String>()).is_ok());
@@ -0,0 +1,398 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    consts::{BACKUP_MANIFEST_FILE, HINT_FILE_NAME, MERGE_FILE_NAME, TXN_FILE},
+    error::{Error, Result},
+    merge::MergeMark,
+    storage::Bitcask,
+    utils::{get_data_file_path, get_data_hint_file_path},
+};
+
+// what a backup holds, written next to its files
+#[derive(Clone, PartialEq, Debug)]
+pub struct BackupManifest {
+    // data files before it were left out, 0 for a full backup
+    pub since_file_id: u32,
+    // where the next incremental backup starts
+    pub next_file_id: u32,
+    // a merge since the last backup replaced the data files before it
+    pub merged_before: Option<u32>,
+    pub files: Vec<String>,
+}
+
+impl BackupManifest {
+    fn write(&self, dir: &Path) -> Result<()> {
+        let mut content = format!("since {}\nnext {}\n", self.since_file_id, self.next_file_id);
+        if let Some(merged_before) = self.merged_before {
+            content += &format!("merged {}\n", merged_before);
+        }
+        for file in self.files.iter() {
+            content += &format!("file {}\n", file);
+        }
+
+        let path = dir.join(BACKUP_MANIFEST_FILE);
+        fs::write(&path, content)?;
+        fs::File::open(path)?.sync_all()?;
+
+        Ok(())
+    }
+
+    pub fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read_to_string(dir.as_ref().join(BACKUP_MANIFEST_FILE))?;
+
+        let mut manifest = Self {
+            since_file_id: 0,
+            next_file_id: 0,
+            merged_before: None,
+            files: Vec::new(),
+        };
+        for line in content.lines() {
+            let broken = Error::BackupBroken("malformed manifest");
+            let (name, value) = line.split_once(' ').ok_or(broken)?;
+            let id = || {
+                value
+                    .parse()
+                    .map_err(|_| Error::BackupBroken("malformed manifest"))
+            };
+
+            match name {
+                "since" => manifest.since_file_id = id()?,
+                "next" => manifest.next_file_id = id()?,
+                "merged" => manifest.merged_before = Some(id()?),
+                "file" => manifest.files.push(value.to_string()),
+                _ => return Err(Error::BackupBroken("malformed manifest")),
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl Bitcask {
+    // copy the database as of now into `dest_dir` while writers go on. the active file
+    // is sealed first, sealed files never change so they are hard linked when possible.
+    // the copy opens like any database, the b+tree index is rebuilt there
+    pub fn backup(&self, dest_dir: impl AsRef<Path>) -> Result<BackupManifest> {
+        self.backup_incremental(dest_dir, 0)
+    }
+
+    // like `backup`, but only data files from `since_file_id` on are copied, it is the
+    // `next_file_id` of the previous backup. `restore` puts the chain back together
+    pub fn backup_incremental(
+        &self,
+        dest_dir: impl AsRef<Path>,
+        since_file_id: u32,
+    ) -> Result<BackupManifest> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let dest_dir = dest_dir.as_ref();
+        create_empty_dir(dest_dir)?;
+
+        // a merge would replace the files while they are copied
+        let _merge_guard = self.merge_lock.lock();
+
+        let mut files = Vec::new();
+        let (file_ids, next_file_id) = {
+            // keep a batch from spanning the copied and uncopied files
+            let _batch_guard = self.batch_lock.lock();
+
+            let mut active_file = self.active_file.write();
+            let mut old_files = self.old_files.write();
+            self.rotate_active_file(&mut active_file, &mut old_files)?;
+
+            // the transaction file as of the same point
+            if copy_if_exists(&self.opts.db_path, dest_dir, TXN_FILE)? {
+                files.push(TXN_FILE.to_string());
+            }
+
+            let mut file_ids: Vec<u32> = old_files.keys().copied().collect();
+            file_ids.sort_unstable();
+            (file_ids, active_file.id)
+        };
+
+        // every merge seals the active file, so one since the last backup
+        // has merged files after `since_file_id`
+        let merged_before = match self.opts.db_path.join(MERGE_FILE_NAME).is_file() {
+            true => Some(MergeMark::read(&self.opts.db_path)?.next_file_id),
+            false => None,
+        }
+        .filter(|next_file_id| *next_file_id > since_file_id);
+
+        // the merged files take the lowest ids
+        let copied = file_ids
+            .into_iter()
+            .filter(|id| *id >= since_file_id || merged_before.is_some_and(|next| *id < next));
+        for id in copied {
+            files.push(link_file(
+                get_data_file_path(&self.opts.db_path, id),
+                dest_dir,
+            )?);
+
+            let hint_file = get_data_hint_file_path(&self.opts.db_path, id);
+            if hint_file.is_file() {
+                files.push(link_file(hint_file, dest_dir)?);
+            }
+        }
+
+        // left by the last merge, they only change when the next one finishes
+        if merged_before.is_some() {
+            for filename in [HINT_FILE_NAME, MERGE_FILE_NAME] {
+                if copy_if_exists(&self.opts.db_path, dest_dir, filename)? {
+                    files.push(filename.to_string());
+                }
+            }
+        }
+
+        let manifest = BackupManifest {
+            since_file_id,
+            next_file_id,
+            merged_before,
+            files,
+        };
+        manifest.write(dest_dir)?;
+        create_active_file(dest_dir, next_file_id)?;
+
+        Ok(manifest)
+    }
+}
+
+// rebuild a database in `dest_dir` from a full backup followed by its incremental ones
+pub fn restore(
+    dest_dir: impl AsRef<Path>,
+    backups: impl IntoIterator<Item = impl AsRef<Path>>,
+) -> Result<()> {
+    let dest_dir = dest_dir.as_ref();
+    create_empty_dir(dest_dir)?;
+
+    let mut next_file_id = 0;
+    for backup in backups {
+        let backup = backup.as_ref();
+        let manifest = BackupManifest::read(backup)?;
+        if manifest.since_file_id != next_file_id {
+            return Err(Error::BackupBroken(
+                "backup does not start where the previous one ends",
+            ));
+        }
+        next_file_id = manifest.next_file_id;
+
+        // the merged files replace everything before the merge
+        if let Some(merged_before) = manifest.merged_before {
+            for id in 0..merged_before {
+                remove_if_exists(get_data_file_path(dest_dir, id))?;
+                remove_if_exists(get_data_hint_file_path(dest_dir, id))?;
+            }
+        }
+
+        // the copies are linked from the backups, a file of an earlier one is
+        // unlinked rather than overwritten
+        for filename in manifest.files.iter() {
+            let dst = dest_dir.join(filename);
+            remove_if_exists(&dst)?;
+            link_or_copy(backup.join(filename), dst)?;
+        }
+    }
+
+    if next_file_id == 0 {
+        return Err(Error::BackupBroken("no backup to restore"));
+    }
+
+    create_active_file(dest_dir, next_file_id)
+}
+
+// writes go to a new empty file, the linked ones are shared and must not change
+fn create_active_file(dir: &Path, file_id: u32) -> Result<()> {
+    fs::File::create(get_data_file_path(dir, file_id))?.sync_all()?;
+    fs::File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn create_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", dir.display()),
+        )));
+    }
+
+    Ok(())
+}
+
+// link `src` into `dest_dir` under the same name, return the name
+fn link_file(src: PathBuf, dest_dir: &Path) -> Result<String> {
+    let filename = src.file_name().unwrap().to_string_lossy().to_string();
+    link_or_copy(&src, dest_dir.join(&filename))?;
+
+    Ok(filename)
+}
+
+// a hard link shares the file, a copy is made across file systems
+fn link_or_copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    if fs::hard_link(&src, &dst).is_err() {
+        fs::copy(&src, &dst)?;
+        fs::File::open(dst)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+// files that are rewritten in place are always copied
+fn copy_if_exists(src_dir: &Path, dest_dir: &Path, filename: &str) -> Result<bool> {
+    let src = src_dir.join(filename);
+    if !src.is_file() {
+        return Ok(false);
+    }
+
+    let dst = dest_dir.join(filename);
+    fs::copy(src, &dst)?;
+    fs::File::open(dst)?.sync_all()?;
+
+    Ok(true)
+}
+
+fn remove_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use crate::{
+        consts::FILE_LOCK,
+        options::{BitcaskOptions, WriteBatchOptions},
+        utils::TestDir,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let path = TestDir::new("bitcask_backup");
+        let dest = TestDir::new("bitcask_backup_dest");
+        let ops = BitcaskOptions {
+            max_file_size: 4 << 10,
+            ..path.options()
+        };
+
+        let bitcask = Bitcask::open(ops.clone())?;
+        for i in 0..300 {
+            bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+        }
+        for i in 0..100 {
+            bitcask.delete(format!("{:04}", i))?;
+        }
+        bitcask.merge()?;
+        let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+        batch.put("batch", "x")?;
+        batch.commit()?;
+
+        // writers keep going while the backup is taken
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (bitcask, stop) = (bitcask.clone(), stop.clone());
+            std::thread::spawn(move || -> Result<()> {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    bitcask.put(format!("new-{}", i), "v")?;
+                    i += 1;
+                }
+                Ok(())
+            })
+        };
+        bitcask.backup(&dest)?;
+        stop.store(true, Ordering::SeqCst);
+        writer.join().unwrap()?;
+
+        assert!(!dest.join(FILE_LOCK).exists());
+        assert!(bitcask.backup(&dest).is_err());
+
+        let backup = Bitcask::open(BitcaskOptions {
+            db_path: dest.to_path_buf(),
+            ..ops
+        })?;
+        assert!(backup.get("0050").is_err());
+        assert_eq!(b"0150".to_vec(), backup.get("0150")?);
+        assert_eq!(b"x".to_vec(), backup.get("batch")?);
+        let key_num = backup.stat()?.key_num;
+        assert!(key_num >= 201 && key_num <= bitcask.stat()?.key_num);
+
+        // both go on independently, the shared files are left alone
+        let manifest = BackupManifest::read(&dest)?;
+        let last_file = get_data_file_path(&path, manifest.next_file_id - 1);
+        let last_file_len = fs::metadata(&last_file)?.len();
+        backup.put("0150", "backup")?;
+        assert_eq!(b"0150".to_vec(), bitcask.get("0150")?);
+        assert_eq!(last_file_len, fs::metadata(&last_file)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_incremental() -> Result<()> {
+        let path = TestDir::new("bitcask_backup_incremental");
+        let backups: Vec<TestDir> = (0..3)
+            .map(|i| TestDir::new(&format!("bitcask_backup_incremental_{}", i)))
+            .collect();
+        let dest = TestDir::new("bitcask_backup_incremental_restore");
+        let ops = BitcaskOptions {
+            max_file_size: 4 << 10,
+            ..path.options()
+        };
+
+        let bitcask = Bitcask::open(ops.clone())?;
+        for i in 0..300 {
+            bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+        }
+        let full = bitcask.backup(&backups[0])?;
+
+        for i in 0..100 {
+            bitcask.put(format!("{:04}", i), "second")?;
+        }
+        let second = bitcask.backup_incremental(&backups[1], full.next_file_id)?;
+        assert_eq!(None, second.merged_before);
+        assert!(!second.files.contains(&"000000000.data".to_string()));
+
+        // the merged files replace the ones of both earlier backups
+        for i in 100..200 {
+            bitcask.delete(format!("{:04}", i))?;
+        }
+        bitcask.merge()?;
+        bitcask.put("0000", "third")?;
+        let third = bitcask.backup_incremental(&backups[2], second.next_file_id)?;
+        assert!(third.merged_before.is_some());
+        assert_eq!(third, BackupManifest::read(&backups[2])?);
+
+        // the chain has to be complete
+        assert!(matches!(
+            restore(&dest, [&backups[0], &backups[2]]),
+            Err(Error::BackupBroken(_))
+        ));
+        fs::remove_dir_all(&dest)?;
+
+        restore(&dest, &backups)?;
+        let restored = Bitcask::open(BitcaskOptions {
+            db_path: dest.to_path_buf(),
+            ..ops
+        })?;
+        assert_eq!(bitcask.stat()?.key_num, restored.stat()?.key_num);
+        assert_eq!(b"third".to_vec(), restored.get("0000")?);
+        assert_eq!(b"second".to_vec(), restored.get("0001")?);
+        assert!(restored.get("0150").is_err());
+        assert_eq!(b"0250".to_vec(), restored.get("0250")?);
+
+        Ok(())
+    }
+}
@@ -1,13 +1,14 @@
 use std::{collections::HashMap, sync::atomic::Ordering};
 
-use anyhow::Result;
 use parking_lot::RwLock;
 
 use crate::{
     data::log_record::Record,
-    key::{check_key_valid, Key},
+    error::{Error, Result},
+    key::{check_key_size, check_key_valid, Key},
     options::WriteBatchOptions,
     storage::Bitcask,
+    subscribe::ChangeEvent,
 };
 
 pub struct BatchWrite<'a> {
@@ -17,7 +18,7 @@ pub struct BatchWrite<'a> {
 }
 
 impl Bitcask {
-    pub fn new_batch_write(&self, opts: WriteBatchOptions) -> Result<BatchWrite> {
+    pub fn new_batch_write(&self, opts: WriteBatchOptions) -> Result<BatchWrite<'_>> {
         Ok(BatchWrite {
             pending: RwLock::new(HashMap::new()),
             storage: self,
@@ -31,6 +32,7 @@ impl<'a> BatchWrite<'a> {
         let key = key.as_ref().to_vec();
         let value = value.as_ref().to_vec();
         check_key_valid(&key)?;
+        check_key_size(&key, self.storage.opts.index_type)?;
 
         self.pending
             .write()
@@ -64,48 +66,48 @@ impl<'a> BatchWrite<'a> {
         }
 
         if pending.len() > self.opts.max_batch_size {
-            return Err(anyhow::Error::msg("batch write: exceed max size!"));
+            return Err(Error::BatchTooLarge(self.opts.max_batch_size));
         }
 
-        let _ = self.storage.batch_lock.lock();
+        let _guard = self.storage.batch_lock.lock();
+        let _shards = self
+            .storage
+            .lock_shards(pending.keys().map(|key| key.as_slice()));
 
         let seq = self.storage.batch_seq.fetch_add(1, Ordering::SeqCst);
 
         let mut index = Vec::with_capacity(pending.len());
 
         for (_key, mut record) in pending {
-            record.enable_batch(seq)?;
+            record.enable_batch(seq);
 
             let pos = self.storage.append_record(&record)?;
 
             index.push((record, pos));
         }
 
-        self.storage.append_record(&Record::batch_finished(seq))?;
+        let finish_pos = self.storage.append_record(&Record::batch_finished(seq))?;
 
         if self.opts.write_sync {
             self.storage.sync()?;
+        } else {
+            self.storage.sync_by_policy()?;
         }
 
-        index.into_iter().try_for_each(|(record, pos)| {
-            let index = self.storage.get_index(&record.key);
-
-            let pos = match record.record_type {
-                crate::data::log_record::RecordType::Deleted => Some(index.delete(&record.key)?),
-                crate::data::log_record::RecordType::Normal => match index.put(record.key, pos) {
-                    Ok(pos) => pos,
-                    Err(e) => return Err(e),
-                },
-            };
-
-            if let Some(pos) = pos {
-                self.storage
-                    .reclaimable
-                    .fetch_add(pos.size as usize, Ordering::SeqCst);
-            }
-
-            Ok(())
-        })?;
+        // a snapshot sees all of the batch or none of it
+        self.storage.publish(
+            || {
+                let _snapshots = self.storage.snapshots.read_recursive();
+                index
+                    .iter()
+                    .try_for_each(|(record, pos)| self.storage.update_index(record, *pos))
+            },
+            |_| Some(ChangeEvent::batch(&index, finish_pos)),
+        )?;
+
+        self.storage
+            .reclaimable
+            .fetch_add(finish_pos.size as usize, Ordering::SeqCst);
 
         Ok(())
     }
@@ -113,19 +115,21 @@ impl<'a> BatchWrite<'a> {
 
 #[cfg(test)]
 mod tests {
-    use anyhow::Result;
-
     use crate::{
+        error::Result,
         options::{BitcaskOptions, WriteBatchOptions},
         storage::Bitcask,
     };
 
     #[test]
     fn test_bitcask_write_batch() -> Result<()> {
-        {
-            let ops = BitcaskOptions::default();
+        let ops = BitcaskOptions {
+            db_path: "/tmp/bitcask_write_batch".into(),
+            ..Default::default()
+        };
 
-            let bitcask = Bitcask::open(ops)?;
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
 
             let batch_ops = WriteBatchOptions::default();
 
@@ -143,7 +147,6 @@ mod tests {
             bitcask.close()?;
         }
 
-        let ops = BitcaskOptions::default();
         let bitcask = Bitcask::open(ops)?;
         for i in 0..1000 {
             let value = bitcask.get(format!("{:09}", i))?;
@@ -0,0 +1,196 @@
+use crate::{
+    data::log_record::Record,
+    error::{Error, Result},
+    key::{check_key_size, check_key_valid},
+    storage::Bitcask,
+};
+
+impl Bitcask {
+    // set `key` to `new` if its value is `expected`, `None` stands for a missing key in both.
+    // return whether it was set. no other writer of the shard gets in between
+    pub fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let key = key.as_ref().to_vec();
+        check_key_valid(&key)?;
+        if new.is_some() {
+            check_key_size(&key, self.opts.index_type)?;
+        }
+
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _shard = self.lock_shard(&key);
+
+        let current = match self.get(&key) {
+            Ok(value) => Some(value),
+            Err(Error::KeyNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+
+        let record = match new {
+            Some(value) => Record::normal(key, value.to_vec()),
+            // already missing
+            None if current.is_none() => return Ok(true),
+            None => Record::deleted(key),
+        };
+
+        let (record, pos) = self.commit_record(record)?;
+        self.apply_write(&record, pos)?;
+
+        Ok(true)
+    }
+
+    // return whether the key was missing and is set now
+    pub fn put_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value.as_ref()))
+    }
+
+    // return whether the key held `expected` and is deleted now
+    pub fn delete_if_equals(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, thread};
+
+    use crate::{
+        dump::{dump, DumpEntry, RecordType},
+        options::BitcaskOptions,
+        utils::get_data_file_path,
+    };
+
+    use super::*;
+
+    // values set by a swap with the ones they replaced
+    type Swaps = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+    #[test]
+    fn test_compare_and_swap() -> Result<()> {
+        let path = "/tmp/bitcask_compare_and_swap";
+        let _ = std::fs::remove_dir_all(path);
+        let bitcask = Bitcask::open(BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        })?;
+
+        assert!(bitcask.put_if_absent("foo", "a")?);
+        assert!(!bitcask.put_if_absent("foo", "b")?);
+        assert!(!bitcask.compare_and_swap("foo", Some(b"b"), Some(b"c"))?);
+        assert!(bitcask.compare_and_swap("foo", Some(b"a"), Some(b"c"))?);
+        assert_eq!(b"c".to_vec(), bitcask.get("foo")?);
+
+        assert!(!bitcask.delete_if_equals("foo", "a")?);
+        assert!(bitcask.delete_if_equals("foo", "c")?);
+        assert!(bitcask.get("foo").is_err());
+        assert!(bitcask.compare_and_swap("foo", None, None)?);
+        assert!(bitcask.put_if_absent("foo", "d")?);
+
+        // concurrent increments through a read and a swap lose no update
+        bitcask.put("counter", 0u64.to_be_bytes())?;
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let bitcask = Arc::clone(&bitcask);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..100 {
+                        loop {
+                            let old = bitcask.get("counter")?;
+                            let new = (u64::from_be_bytes(old.clone().try_into().unwrap()) + 1)
+                                .to_be_bytes();
+                            if bitcask.compare_and_swap("counter", Some(&old), Some(&new))? {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(800u64.to_be_bytes().to_vec(), bitcask.get("counter")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap_with_writers() -> Result<()> {
+        let path = "/tmp/bitcask_compare_and_swap_writers";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
+        let bitcask = Bitcask::open(ops.clone())?;
+
+        // plain writers put and delete the key while the others swap it
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let bitcask = Arc::clone(&bitcask);
+                thread::spawn(move || -> Result<Swaps> {
+                    let mut swapped = Vec::new();
+                    for i in 0..200 {
+                        let value = format!("{}-{}", t, i).into_bytes();
+                        match t % 2 {
+                            0 if i % 4 == 0 => bitcask.delete("slot")?,
+                            0 => bitcask.put("slot", &value)?,
+                            _ => {
+                                let old = match bitcask.get("slot") {
+                                    Ok(old) => Some(old),
+                                    Err(Error::KeyNotFound) => None,
+                                    Err(e) => return Err(e),
+                                };
+                                if bitcask.compare_and_swap("slot", old.as_deref(), Some(&value))? {
+                                    swapped.push((value, old));
+                                }
+                            }
+                        }
+                    }
+                    Ok(swapped)
+                })
+            })
+            .collect();
+        let mut swapped = HashMap::new();
+        for handle in handles {
+            swapped.extend(handle.join().unwrap()?);
+        }
+        assert!(!swapped.is_empty());
+
+        // every swap directly follows the value it expected in the log
+        let mut current = None;
+        for entry in dump(get_data_file_path(path, 0))? {
+            let DumpEntry::Record(record) = entry else {
+                panic!("garbage in the data file");
+            };
+            if let Some(expected) = swapped.get(&record.value) {
+                assert_eq!(expected, &current);
+            }
+            current = match record.record_type {
+                RecordType::Normal => Some(record.value),
+                RecordType::Deleted => None,
+            };
+        }
+
+        // the last record is the indexed one, also after a reopen
+        assert_eq!(current, bitcask.get("slot").ok());
+        drop(bitcask);
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(current, bitcask.get("slot").ok());
+
+        Ok(())
+    }
+}
@@ -1,10 +1,10 @@
-use std::path::Path;
-
-use anyhow::{Ok, Result};
+use std::{io, path::Path};
 
 use crate::{
-    consts::{HINT_FILE_NAME, MERGE_FILE_NAME},
-    file::{new_io, IO},
+    consts::{DATA_FILE_SUFFIX, HINT_FILE_NAME, MERGE_FILE_NAME},
+    error::{Error, Result},
+    file::{new_io, system_file::SystemFile, IO},
+    options::IoType,
     utils::get_data_file_path,
 };
 
@@ -18,22 +18,63 @@ pub struct DataFile {
 
 impl DataFile {
     pub fn new(path: impl AsRef<Path>, file_id: u32) -> Result<Self> {
+        Self::new_with_io_type(path, file_id, IoType::StandardFIO)
+    }
+
+    pub fn new_with_io_type(path: impl AsRef<Path>, file_id: u32, io_type: IoType) -> Result<Self> {
+        let file_path = get_data_file_path(path, file_id);
+
+        Ok(Self {
+            id: file_id,
+            write_offset: 0,
+            io: new_io(file_path, io_type)?,
+        })
+    }
+
+    // read a data file another process is writing, a read past its end comes back short
+    // instead of faulting like a memory map would
+    pub fn open_read_only(path: impl AsRef<Path>, file_id: u32) -> Result<Self> {
         let file_path = get_data_file_path(path, file_id);
 
         Ok(Self {
             id: file_id,
             write_offset: 0,
-            io: new_io(file_path)?,
+            io: Box::new(SystemFile::open_read_only(file_path)?),
         })
     }
 
+    // reopen the file under `path` with another io type
+    pub fn set_io_type(&mut self, path: impl AsRef<Path>, io_type: IoType) -> Result<()> {
+        self.io = new_io(get_data_file_path(path, self.id), io_type)?;
+        Ok(())
+    }
+
     pub fn hint_file(path: impl AsRef<Path>) -> Result<Self> {
         let file_path = path.as_ref().join(HINT_FILE_NAME);
 
         Ok(Self {
             id: 0,
             write_offset: 0,
-            io: new_io(file_path)?,
+            io: new_io(file_path, IoType::StandardFIO)?,
+        })
+    }
+
+    // any file in the record format, the id of a data file is parsed from its name
+    pub fn from_path(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        if !file_path.is_file() {
+            return Err(Error::Io(io::ErrorKind::NotFound.into()));
+        }
+
+        let id = file_path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_suffix(DATA_FILE_SUFFIX)?.parse().ok())
+            .unwrap_or(0);
+
+        Ok(Self {
+            id,
+            write_offset: 0,
+            io: new_io(file_path, IoType::MemoryMap)?,
         })
     }
 
@@ -43,41 +84,104 @@ impl DataFile {
         Ok(Self {
             id: 0,
             write_offset: 0,
-            io: new_io(file_path)?,
+            io: new_io(file_path, IoType::StandardFIO)?,
         })
     }
 
     pub fn sync(&self) -> Result<()> {
         self.io.sync()
     }
-
-    pub fn padding(&mut self, max: u64) -> Result<()> {
-        let len = max - self.write_offset;
-        let buf = vec![0; len as usize];
-        self.io.write(&buf, self.write_offset)?;
-        Ok(())
-    }
 }
 
 impl DataFile {
     pub fn write_record(&mut self, record: &Record) -> Result<u32> {
-        let encode_data = record.encode();
-        let write_size = self.io.write(&encode_data, self.write_offset)?;
+        self.write(&record.encode())
+    }
+
+    // append already encoded records
+    pub fn write(&mut self, buf: &[u8]) -> Result<u32> {
+        let write_size = self.io.write(buf, self.write_offset)?;
 
         self.write_offset += write_size as u64;
 
         Ok(write_size)
     }
 
+    pub fn size(&self) -> Result<u64> {
+        self.io.size()
+    }
+
+    // reading at the end of the file is `UnexpectedEof`, a partial size field is corrupted
     pub fn read_record(&self, offset: u64) -> Result<RecordReader> {
-        RecordReader::decode(self.io.as_ref(), offset)
+        let mut size = [0; 8];
+        match self.io.read(&mut size, offset)? {
+            0 => return Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+            8 => {}
+            _ => {
+                return Err(Error::Corrupted {
+                    file_id: self.id,
+                    offset,
+                })
+            }
+        }
+
+        self.read_record_with_size(offset, u64::from_be_bytes(size))
     }
 
     pub fn read_record_with_size(&self, offset: u64, size: u64) -> Result<RecordReader> {
+        let corrupted = Error::Corrupted {
+            file_id: self.id,
+            offset,
+        };
+        if size < RecordReader::MIN_SIZE as u64 {
+            return Err(corrupted);
+        }
+
+        // cut off by the end of the file, a torn write or one still in progress
+        let mut buf = vec![0u8; size as usize];
+        if (self.io.read(&mut buf, offset)? as u64) < size {
+            return Err(corrupted);
+        }
+
+        RecordReader::decode_from_vec(buf).ok_or(corrupted)
+    }
+
+    // the record at `offset` even if its crc32 is wrong, `None` if no record fits there
+    pub fn read_record_unchecked(&self, offset: u64) -> Result<Option<RecordReader>> {
+        let mut size = [0; 8];
+        if self.io.read(&mut size, offset)? < size.len() as u32 {
+            return Ok(None);
+        }
+
+        let size = u64::from_be_bytes(size);
+        if size < RecordReader::MIN_SIZE as u64 || size > self.size()? - offset {
+            return Ok(None);
+        }
+
         let mut buf = vec![0u8; size as usize];
         self.io.read(&mut buf, offset)?;
 
-        RecordReader::decode_from_vec(buf)
+        Ok(RecordReader::decode_unchecked(buf))
+    }
+
+    // offset of the first valid record at or after `offset`, `None` if there is none
+    pub fn next_valid_record(&self, mut offset: u64) -> Result<Option<u64>> {
+        let file_size = self.size()?;
+
+        while offset + RecordReader::MIN_SIZE as u64 <= file_size {
+            let mut size = [0; 8];
+            self.io.read(&mut size, offset)?;
+            let size = u64::from_be_bytes(size);
+
+            // sizes running past the end are rejected before allocating a buffer for them
+            if size <= file_size - offset && self.read_record_with_size(offset, size).is_ok() {
+                return Ok(Some(offset));
+            }
+
+            offset += 1;
+        }
+
+        Ok(None)
     }
 }
 
@@ -186,7 +290,7 @@ mod tests {
 
         // type is Batch
         let mut record = Record::normal("foo".into(), "f".into());
-        record.enable_batch(1)?;
+        record.enable_batch(1);
 
         data_file.write_record(&record)?;
 
@@ -210,4 +314,33 @@ mod tests {
         assert_eq!(record.record_type, read_record.record_type);
         Ok(())
     }
+
+    #[test]
+    fn read_corrupted() -> Result<()> {
+        let path = temp_dir().join("bitcask_datafile_corrupted");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path)?;
+
+        let mut data_file = DataFile::new(&path, 3)?;
+        let size = data_file.write_record(&Record::normal("foo".into(), "bar".into()))?;
+        data_file.write_record(&Record::normal("bar".into(), "foo".into()))?;
+
+        // flip the last byte of the value of the second record
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(get_data_file_path(&path, 3))?;
+        std::os::unix::fs::FileExt::write_at(&file, &[0xff], data_file.write_offset - 5)?;
+
+        assert!(data_file.read_record(0).is_ok());
+        assert!(matches!(
+            data_file.read_record(size as u64),
+            Err(Error::Corrupted { file_id: 3, offset }) if offset == size as u64
+        ));
+        assert!(matches!(
+            data_file.read_record(data_file.write_offset),
+            Err(Error::Io(_))
+        ));
+
+        Ok(())
+    }
 }
@@ -1,14 +1,10 @@
 use std::mem::size_of;
 
-use anyhow::Ok;
 use bytes::{Buf, BufMut};
 
-use crate::{
-    file::IO,
-    key::{Key, Value},
-};
+use crate::key::{Key, Value};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct RecordPosition {
     pub file_id: u32,
     pub offset: u64,
@@ -34,7 +30,7 @@ impl RecordPosition {
     }
 
     pub fn decode(data: &[u8]) -> Self {
-        let mut data = &data[..];
+        let mut data = data;
         let file_id = data.get_u32();
         let offset = data.get_u64();
         let size = data.get_u32();
@@ -58,16 +54,26 @@ pub enum BatchState {
     Enable(u64),
     Finish(u64),
     Disable,
+    // a version written or removed by the transaction with the ts
+    Txn(u64),
+    // the transaction with the ts is committed
+    TxnCommit(u64),
+    // a copy a merge keeps for live snapshots, it is never indexed
+    Snapshot,
 }
 
+// set on the record type byte when an expire timestamp follows the batch state
+const EXPIRE_FLAG: u8 = 0x80;
+
 impl TryFrom<u8> for RecordType {
-    type Error = anyhow::Error;
+    // the unknown type byte
+    type Error = u8;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(Self::Deleted),
             1 => Ok(Self::Normal),
-            _ => Err(anyhow::Error::msg("wrong record type!")),
+            _ => Err(value),
         }
     }
 }
@@ -79,6 +85,9 @@ pub struct Record {
     pub value: Value,
 
     pub batch_state: BatchState,
+
+    // unix timestamp in milliseconds after which the record is gone
+    pub expire_at: Option<u64>,
 }
 
 impl Record {
@@ -88,6 +97,7 @@ impl Record {
             value,
             record_type: RecordType::Normal,
             batch_state: BatchState::Disable,
+            expire_at: None,
         }
     }
 
@@ -97,6 +107,7 @@ impl Record {
             value: Default::default(),
             record_type: RecordType::Deleted,
             batch_state: BatchState::Disable,
+            expire_at: None,
         }
     }
 
@@ -106,11 +117,42 @@ impl Record {
             value: Default::default(),
             record_type: RecordType::Normal,
             batch_state: BatchState::Finish(seq),
+            expire_at: None,
+        }
+    }
+
+    pub fn txn_committed(ts: u64) -> Self {
+        Self {
+            key: "TC".into(),
+            value: Default::default(),
+            record_type: RecordType::Normal,
+            batch_state: BatchState::TxnCommit(ts),
+            expire_at: None,
+        }
+    }
+
+    pub fn normal_with_expire(key: Key, value: Value, expire_at: u64) -> Self {
+        Self {
+            expire_at: Some(expire_at),
+            ..Self::normal(key, value)
+        }
+    }
+
+    // same record with the value replaced by where it was written
+    pub fn to_hint(&self, pos: RecordPosition) -> Self {
+        Self {
+            key: self.key.clone(),
+            value: pos.encode(),
+            ..*self
         }
     }
 
-    pub fn merge_finished(next_unmerged_file_id: u32) -> Self {
-        Self::normal("MF".into(), next_unmerged_file_id.to_be_bytes().into())
+    pub fn merge_finished(next_unmerged_file_id: u32, merged_file_num: u32) -> Self {
+        let mut value = Vec::with_capacity(8);
+        value.put_u32(next_unmerged_file_id);
+        value.put_u32(merged_file_num);
+
+        Self::normal("MF".into(), value)
     }
 }
 
@@ -121,24 +163,42 @@ impl Record {
 
         buf.put_u64(size as u64);
 
-        let record_type = match self.record_type {
-            RecordType::Deleted => 0 as u8,
-            RecordType::Normal => 1 as u8,
+        let mut record_type = match self.record_type {
+            RecordType::Deleted => 0_u8,
+            RecordType::Normal => 1_u8,
         };
+        if self.expire_at.is_some() {
+            record_type |= EXPIRE_FLAG;
+        }
         buf.put_u8(record_type);
 
         match self.batch_state {
             BatchState::Enable(seq) => {
-                buf.put_u8(0 as u8);
+                buf.put_u8(0_u8);
                 buf.put_u64(seq);
             }
             BatchState::Finish(seq) => {
-                buf.put_u8(1 as u8);
+                buf.put_u8(1_u8);
                 buf.put_u64(seq)
             }
             BatchState::Disable => {
-                buf.put_u8(2 as u8);
+                buf.put_u8(2_u8);
+            }
+            BatchState::Txn(ts) => {
+                buf.put_u8(3_u8);
+                buf.put_u64(ts);
             }
+            BatchState::TxnCommit(ts) => {
+                buf.put_u8(4_u8);
+                buf.put_u64(ts);
+            }
+            BatchState::Snapshot => {
+                buf.put_u8(5_u8);
+            }
+        }
+
+        if let Some(expire_at) = self.expire_at {
+            buf.put_u64(expire_at);
         }
 
         buf.put_u32(self.key.len() as u32);
@@ -168,20 +228,28 @@ impl Record {
         match self.batch_state {
             BatchState::Enable(_) => res += std::mem::size_of::<u64>(),
             BatchState::Finish(_) => res += std::mem::size_of::<u64>(),
-            BatchState::Disable => {}
+            BatchState::Txn(_) => res += std::mem::size_of::<u64>(),
+            BatchState::TxnCommit(_) => res += std::mem::size_of::<u64>(),
+            BatchState::Disable | BatchState::Snapshot => {}
+        }
+
+        if self.expire_at.is_some() {
+            res += std::mem::size_of::<u64>();
         }
 
         res
     }
 
-    pub fn enable_batch(&mut self, seq: u64) -> Result<(), anyhow::Error> {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+
+    pub fn enable_batch(&mut self, seq: u64) {
         self.batch_state = BatchState::Enable(seq);
-        Ok(())
     }
 
-    pub fn disable_batch(&mut self) -> Result<(), anyhow::Error> {
+    pub fn disable_batch(&mut self) {
         self.batch_state = BatchState::Disable;
-        Ok(())
     }
 }
 
@@ -199,78 +267,102 @@ pub struct RecordReader {
     value_size: u32,
     pub(crate) record_type: RecordType,
     pub(crate) batch_state: BatchState,
+    pub(crate) expire_at: Option<u64>,
 }
 
 impl RecordReader {
-    pub fn decode(io: &dyn IO, offset: u64) -> Result<Self, anyhow::Error> {
-        let mut size = [0; 8];
-        io.read(&mut size, offset)?;
-        let size = u64::from_be_bytes(size);
-
-        if size == 0 {
-            return Err(anyhow::Error::msg("read record with size == 0"));
-        }
-
-        let mut data = vec![0u8; size as usize];
-
-        io.read(&mut data, offset)?;
+    // size, type, batch state, key size, value size and crc32 of the smallest record
+    pub const MIN_SIZE: usize = 8 + 1 + 1 + 4 + 4 + 4;
 
-        Self::decode_from_vec(data)
+    // `None` if `buf` does not hold a valid record
+    pub fn decode_from_vec(buf: Vec<u8>) -> Option<Self> {
+        Self::decode_unchecked(buf).filter(|record| record.check_crc())
     }
 
-    pub fn decode_from_vec(buf: Vec<u8>) -> Result<Self, anyhow::Error> {
+    // decode without checking the crc32, `None` if the fields do not fit in `buf`
+    pub fn decode_unchecked(buf: Vec<u8>) -> Option<Self> {
+        if buf.len() < Self::MIN_SIZE {
+            return None;
+        }
+
         let box_data = buf.clone().into_boxed_slice();
-        let mut data = buf.as_slice();
+        // the crc32 is not part of the fields
+        let mut data = &buf[..buf.len() - 4];
 
         let mut index = 0;
 
-        let checksum = u32::from_be_bytes(*data.last_chunk::<4>().unwrap());
-        let crc32 = get_crc_32(&data[..data.len() - 4]);
-
-        if checksum != crc32 {
-            return Err(anyhow::Error::msg("check crc32 error!"));
-        }
-
         // skip size field
         data.get_u64();
         index += 8;
 
-        let record_type = RecordType::try_from(data.get_u8())?;
+        let type_byte = data.get_u8();
+        let record_type = RecordType::try_from(type_byte & !EXPIRE_FLAG).ok()?;
         index += 1;
 
         let batch_state = match data.get_u8() {
-            0 => {
-                let seq = data.get_u64();
-                index += 9;
-                BatchState::Enable(seq)
-            }
-            1 => {
+            state @ (0 | 1 | 3 | 4) => {
+                if data.remaining() < 8 {
+                    return None;
+                }
                 let seq = data.get_u64();
                 index += 9;
-                BatchState::Finish(seq)
+
+                match state {
+                    0 => BatchState::Enable(seq),
+                    1 => BatchState::Finish(seq),
+                    3 => BatchState::Txn(seq),
+                    _ => BatchState::TxnCommit(seq),
+                }
             }
             2 => {
                 index += 1;
                 BatchState::Disable
             }
-            _ => unreachable!(),
+            5 => {
+                index += 1;
+                BatchState::Snapshot
+            }
+            _ => return None,
         };
 
+        let mut expire_at = None;
+        if type_byte & EXPIRE_FLAG != 0 {
+            if data.remaining() < 8 {
+                return None;
+            }
+            expire_at = Some(data.get_u64());
+            index += 8;
+        }
+
+        if data.remaining() < 8 {
+            return None;
+        }
         let key_len = data.get_u32() as usize;
         index += 4;
         let value_len = data.get_u32() as usize;
         index += 4;
 
-        Ok(Self {
+        if index as usize + key_len + value_len + 4 != box_data.len() {
+            return None;
+        }
+
+        Some(Self {
             data: box_data,
             key_value_start: index,
             key_size: key_len as u32,
             value_size: value_len as u32,
             record_type,
             batch_state,
+            expire_at,
         })
     }
 
+    pub fn check_crc(&self) -> bool {
+        let (data, checksum) = self.data.split_at(self.data.len() - 4);
+
+        get_crc_32(data) == u32::from_be_bytes(checksum.try_into().unwrap())
+    }
+
     pub fn key(&self) -> &[u8] {
         &self.data[self.key_value_start as usize..(self.key_value_start + self.key_size) as usize]
     }
@@ -283,6 +375,11 @@ impl RecordReader {
     pub fn size(&self) -> usize {
         self.data.len()
     }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+
     pub fn to_record(&self) -> Record {
         let key = self.key();
         let value = self.value();
@@ -292,6 +389,7 @@ impl RecordReader {
             key: key.to_vec(),
             value: value.to_vec(),
             batch_state: self.batch_state,
+            expire_at: self.expire_at,
         }
     }
 }
@@ -307,6 +405,7 @@ mod tests {
             key: "cxk".into(),
             value: "kk".into(),
             batch_state: BatchState::Disable,
+            expire_at: None,
         };
 
         let encode_data = record.encode();
@@ -316,4 +415,23 @@ mod tests {
         assert_eq!(reader.key(), "cxk".as_bytes());
         assert_eq!(reader.value(), "kk".as_bytes());
     }
+
+    #[test]
+    fn record_with_expire_encode_and_decode() {
+        let mut record = Record::normal_with_expire("foo".into(), "bar".into(), 1000);
+        record.enable_batch(7);
+
+        let encode_data = record.encode();
+        assert_eq!(record.get_encode_len(), encode_data.len());
+
+        let reader = RecordReader::decode_from_vec(encode_data).unwrap();
+
+        assert_eq!(reader.key(), "foo".as_bytes());
+        assert_eq!(reader.value(), "bar".as_bytes());
+        assert_eq!(reader.record_type, RecordType::Normal);
+        assert_eq!(reader.batch_state, BatchState::Enable(7));
+        assert_eq!(reader.expire_at, Some(1000));
+        assert!(!reader.is_expired(999));
+        assert!(reader.is_expired(1000));
+    }
 }
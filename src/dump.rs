@@ -0,0 +1,159 @@
+use std::path::Path;
+
+pub use crate::data::log_record::{BatchState, RecordPosition, RecordType};
+
+use crate::{
+    consts::{DATA_HINT_FILE_SUFFIX, HINT_FILE_NAME},
+    data::datafile::DataFile,
+    error::Result,
+};
+
+pub enum DumpEntry {
+    Record(DumpRecord),
+    // bytes that do not frame a record, up to the next valid one
+    Garbage { offset: u64, len: u64 },
+}
+
+pub struct DumpRecord {
+    pub offset: u64,
+    pub size: u64,
+    pub record_type: RecordType,
+    pub batch_state: BatchState,
+    pub expire_at: Option<u64>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    // the value decoded as where the record lives, for records of hint files
+    pub position: Option<RecordPosition>,
+    pub crc_ok: bool,
+}
+
+// every record of a data, hint or merge file in order, including the ones with a bad crc32
+pub fn dump(path: impl AsRef<Path>) -> Result<Vec<DumpEntry>> {
+    let path = path.as_ref();
+    let file = DataFile::from_path(path)?;
+
+    let hint = path.file_name().is_some_and(|name| {
+        name == HINT_FILE_NAME || name.to_string_lossy().ends_with(DATA_HINT_FILE_SUFFIX)
+    });
+
+    let file_size = file.size()?;
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < file_size {
+        let Some(record) = file.read_record_unchecked(offset)? else {
+            let end = file.next_valid_record(offset + 1)?.unwrap_or(file_size);
+            entries.push(DumpEntry::Garbage {
+                offset,
+                len: end - offset,
+            });
+            offset = end;
+            continue;
+        };
+
+        // tombstones of a merge have no position
+        let position = match record.value().len() {
+            16 if hint => Some(RecordPosition::decode(record.value())),
+            _ => None,
+        };
+
+        entries.push(DumpEntry::Record(DumpRecord {
+            offset,
+            size: record.size() as u64,
+            record_type: record.record_type,
+            batch_state: record.batch_state,
+            expire_at: record.expire_at,
+            key: record.key().to_vec(),
+            value: record.value().to_vec(),
+            position,
+            crc_ok: record.check_crc(),
+        }));
+        offset += record.size() as u64;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, os::unix::fs::FileExt};
+
+    use crate::{
+        consts::MERGE_FILE_NAME,
+        options::{BitcaskOptions, WriteBatchOptions},
+        storage::Bitcask,
+        utils::{get_data_file_path, get_data_hint_file_path},
+    };
+
+    use super::*;
+
+    fn records(entries: Vec<DumpEntry>) -> Vec<DumpRecord> {
+        entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                DumpEntry::Record(record) => Some(record),
+                DumpEntry::Garbage { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let path = "/tmp/bitcask_dump";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let bitcask = Bitcask::open(ops)?;
+        bitcask.put("foo", "bar")?;
+        let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+        batch.put("batch", "x")?;
+        batch.commit()?;
+        bitcask.delete("foo")?;
+
+        let entries = records(dump(get_data_file_path(path, 0))?);
+        assert_eq!(4, entries.len());
+        assert_eq!(b"bar".to_vec(), entries[0].value);
+        assert!(matches!(entries[1].batch_state, BatchState::Enable(seq) if seq == 1));
+        assert_eq!(b"BF".to_vec(), entries[2].key);
+        assert!(matches!(entries[2].batch_state, BatchState::Finish(1)));
+        assert_eq!(RecordType::Deleted, entries[3].record_type);
+        assert!(entries
+            .iter()
+            .all(|record| record.crc_ok && record.position.is_none()));
+
+        // a bad crc32 is shown, a broken size field turns into garbage
+        let copy = "/tmp/bitcask_dump_corrupted.data";
+        std::fs::copy(get_data_file_path(path, 0), copy)?;
+        let file = OpenOptions::new().write(true).open(copy)?;
+        file.write_at(&[0xff], entries[0].size - 5)?;
+        file.write_at(&[0xff], entries[1].offset)?;
+
+        let corrupted = dump(copy)?;
+        assert_eq!(4, corrupted.len());
+        assert!(matches!(&corrupted[0], DumpEntry::Record(record) if !record.crc_ok));
+        assert!(matches!(
+            corrupted[1],
+            DumpEntry::Garbage { offset, len } if offset == entries[1].offset && len > 0
+        ));
+
+        // hints decode into positions, the merge mark is visible
+        for i in 0..100 {
+            bitcask.put(format!("{:04}", i), format!("{:064}", i))?;
+        }
+        let hints = records(dump(get_data_hint_file_path(path, 0))?);
+        assert!(hints.len() > 4);
+        assert_eq!(Some(RecordPosition::new(0, 0, 28)), hints[0].position);
+
+        bitcask.merge()?;
+        let marks = records(dump(Path::new(path).join(MERGE_FILE_NAME))?);
+        assert_eq!(b"MF".to_vec(), marks[0].key);
+        assert!(records(dump(Path::new(path).join(HINT_FILE_NAME))?)
+            .iter()
+            .all(|hint| hint.position.is_some()));
+
+        Ok(())
+    }
+}
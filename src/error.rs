@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("key not found")]
+    KeyNotFound,
+
+    #[error("key is empty")]
+    KeyEmpty,
+
+    #[error("key is too large: {size} bytes, max {max} bytes")]
+    KeyTooLarge { size: usize, max: usize },
+
+    // the record at `offset` of file `file_id` failed to decode or checksum
+    #[error("corrupted record in file {file_id} at offset {offset}")]
+    Corrupted { file_id: u32, offset: u64 },
+
+    #[error("data file {0} not found")]
+    DataFileNotFound(u32),
+
+    #[error("transaction conflict")]
+    TxnConflict,
+
+    #[error("merge is in progress")]
+    MergeInProgress,
+
+    #[error("merge finished file is missing or broken")]
+    MergeFileBroken,
+
+    #[error("database is locked by another process")]
+    DatabaseLocked,
+
+    #[error("database is opened read only")]
+    ReadOnly,
+
+    #[error("batch exceeds max size of {0} records")]
+    BatchTooLarge(usize),
+
+    #[error("invalid options: {0}")]
+    InvalidOptions(&'static str),
+
+    #[error("index is broken: {0}")]
+    IndexBroken(&'static str),
+
+    #[error("unsupported operation: {0}")]
+    Unsupported(&'static str),
+
+    #[error("backup is broken: {0}")]
+    BackupBroken(&'static str),
+
+    // every writer of the failed group gets the same error
+    #[error("group commit failed: {0}")]
+    GroupCommit(Arc<Error>),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,87 @@
+use std::{fs::OpenOptions, io, path::Path};
+
+use memmap2::Mmap;
+
+use crate::error::{Error, Result};
+
+use super::IO;
+
+// read only mapping of an immutable data file
+pub struct MmapFile {
+    map: Mmap,
+}
+
+impl MmapFile {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        // existing files are only opened for reading, they may be read only
+        let fd = match OpenOptions::new().read(true).open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(path)?,
+            fd => fd?,
+        };
+
+        let map = unsafe { Mmap::map(&fd) }?;
+
+        Ok(Self { map })
+    }
+}
+
+impl IO for MmapFile {
+    fn write(&mut self, _buf: &[u8], _offset: u64) -> Result<u32> {
+        Err(Error::Unsupported("write to a memory mapped file"))
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<u32> {
+        let offset = (offset as usize).min(self.map.len());
+        let len = buf.len().min(self.map.len() - offset);
+
+        buf[..len].copy_from_slice(&self.map[offset..offset + len]);
+
+        Ok(len as u32)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use crate::file::system_file::SystemFile;
+
+    use super::*;
+
+    #[test]
+    fn read() -> Result<()> {
+        let path = temp_dir().join("bitcask_mmap_read");
+        let _ = std::fs::remove_file(&path);
+
+        let empty = MmapFile::new(&path)?;
+        assert_eq!(0, empty.read(&mut [0; 4], 0)?);
+
+        let mut file = SystemFile::new(&path)?;
+        file.write(b"hello mmap", 0)?;
+        file.sync()?;
+
+        let mut mmap = MmapFile::new(&path)?;
+        let mut buf = [0; 4];
+        assert_eq!(4, mmap.read(&mut buf, 6)?);
+        assert_eq!(b"mmap", &buf);
+
+        assert_eq!(2, mmap.read(&mut buf, 8)?);
+        assert_eq!(0, mmap.read(&mut buf, 100)?);
+        assert_eq!(10, mmap.size()?);
+        assert!(mmap.write(b"no", 0).is_err());
+
+        Ok(())
+    }
+}
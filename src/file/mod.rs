@@ -1,8 +1,11 @@
 use std::path::Path;
 
-use anyhow::{Ok, Result};
+use mmap::MmapFile;
 use system_file::SystemFile;
 
+use crate::{error::Result, options::IoType};
+
+pub mod mmap;
 pub mod system_file;
 // abstract IO interface
 pub trait IO: Send + Sync {
@@ -11,10 +14,15 @@ pub trait IO: Send + Sync {
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<u32>;
 
     fn sync(&self) -> Result<()>;
+
+    fn size(&self) -> Result<u64>;
 }
 
-pub fn new_io(path: impl AsRef<Path>) -> Result<Box<dyn IO>> {
-    Ok(Box::new(SystemFile::new(path)?))
+pub fn new_io(path: impl AsRef<Path>, io_type: IoType) -> Result<Box<dyn IO>> {
+    match io_type {
+        IoType::StandardFIO => Ok(Box::new(SystemFile::new(path)?)),
+        IoType::MemoryMap => Ok(Box::new(MmapFile::new(path)?)),
+    }
 }
 
 
@@ -4,6 +4,8 @@ use std::{
     path::Path,
 };
 
+use crate::error::Result;
+
 use super::IO;
 
 pub struct SystemFile {
@@ -11,36 +13,38 @@ pub struct SystemFile {
 }
 
 impl SystemFile {
-    pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        OpenOptions::new()
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let fd = OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
-            .open(path)
-            .map(|fd| Self { fd })
-            .map_err(|_| anyhow::Error::msg("open system file error!"))
+            .open(path)?;
+
+        Ok(Self { fd })
+    }
+
+    // an existing file only read, it may be truncated or grow under us
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let fd = OpenOptions::new().read(true).open(path)?;
+
+        Ok(Self { fd })
     }
 }
 
 impl IO for SystemFile {
-    fn write(&mut self, buf: &[u8], offset: u64) -> anyhow::Result<u32> {
-        self.fd
-            .write_at(buf, offset)
-            .map(|size| size as u32)
-            .map_err(|_| anyhow::Error::msg("system file write buf error!"))
+    fn write(&mut self, buf: &[u8], offset: u64) -> Result<u32> {
+        Ok(self.fd.write_at(buf, offset)? as u32)
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<u32> {
+        Ok(self.fd.read_at(buf, offset)? as u32)
     }
 
-    fn read(&self, buf: &mut [u8], offset: u64) -> anyhow::Result<u32> {
-        self.fd
-            .read_at(buf, offset)
-            .map(|size| size as u32)
-            .map_err(|_| anyhow::Error::msg("system file read buf error!"))
+    fn sync(&self) -> Result<()> {
+        Ok(self.fd.sync_all()?)
     }
 
-    fn sync(&self) -> anyhow::Result<()> {
-        self.fd
-            .sync_all()
-            .map_err(|_| anyhow::Error::msg("system file sync error1"))
+    fn size(&self) -> Result<u64> {
+        Ok(self.fd.metadata()?.len())
     }
 }
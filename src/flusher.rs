@@ -0,0 +1,122 @@
+use std::{
+    sync::{atomic::Ordering, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+
+use crate::{error::Result, options::SyncPolicy, storage::Bitcask};
+
+// background thread syncing the active file for `SyncPolicy::Interval`
+pub(crate) struct Flusher {
+    shutdown: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Flusher {
+    pub(crate) fn spawn(storage: Weak<Bitcask>, interval: Duration) -> Self {
+        let (shutdown, rx) = bounded(1);
+
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                // the storage has been dropped
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+
+                // nothing written since the last sync
+                if storage.bytes_written.load(Ordering::SeqCst) == 0 {
+                    continue;
+                }
+
+                if let Err(e) = storage.sync() {
+                    log::error!("background sync error: {}", e);
+                }
+            }
+        });
+
+        Self { shutdown, handle }
+    }
+
+    pub(crate) fn stop(self) {
+        let _ = self.shutdown.send(());
+
+        if self.handle.join().is_err() {
+            log::error!("background sync thread panicked!");
+        }
+    }
+}
+
+impl Bitcask {
+    // sync at the end of a batch or transaction if the policy asks for it
+    pub(crate) fn sync_by_policy(&self) -> Result<()> {
+        let written = self.bytes_written.load(Ordering::SeqCst);
+        match self.opts.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::EveryBytes(bytes) if written >= bytes => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::{BitcaskOptions, WriteBatchOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_sync_policy() -> Result<()> {
+        let path = "/tmp/bitcask_sync_policy";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            sync_policy: SyncPolicy::EveryBytes(1 << 10),
+            ..Default::default()
+        };
+
+        // the counter starts over at every sync
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..100 {
+                bitcask.put(format!("{:04}", i), format!("{:032}", i))?;
+                assert!(bitcask.get_size() < 1 << 10);
+            }
+
+            // a batch follows the policy unless it asks for a sync
+            let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+            batch.put("batch", "x")?;
+            batch.commit()?;
+            assert!(bitcask.get_size() > 0);
+
+            let mut batch = bitcask.new_batch_write(WriteBatchOptions {
+                write_sync: true,
+                ..Default::default()
+            })?;
+            batch.put("batch", "y")?;
+            batch.commit()?;
+            assert_eq!(0, bitcask.get_size());
+            bitcask.close()?;
+        }
+
+        let bitcask = Bitcask::open(BitcaskOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(10)),
+            ..ops
+        })?;
+        bitcask.put("foo", "bar")?;
+        assert!(bitcask.get_size() > 0);
+
+        let mut waited = 0;
+        while bitcask.get_size() > 0 && waited < 100 {
+            thread::sleep(Duration::from_millis(10));
+            waited += 1;
+        }
+        assert_eq!(0, bitcask.get_size());
+
+        bitcask.close()?;
+        assert!(bitcask.flusher.lock().is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crossbeam_channel::{bounded, Sender};
+use parking_lot::Mutex;
+
+use crate::{
+    data::{
+        datafile::DataFile,
+        log_record::{Record, RecordPosition},
+    },
+    error::{Error, Result},
+    options::SyncPolicy,
+    storage::Bitcask,
+};
+
+type Waiter = (Record, Sender<Result<(Record, RecordPosition)>>);
+
+// records of concurrent writers waiting for a sync, a leader writes and syncs them together
+#[derive(Default)]
+pub(crate) struct CommitQueue {
+    pending: Mutex<Vec<Waiter>>,
+    // held by the leader while it writes a group
+    leader: Mutex<()>,
+    // groups written so far
+    groups: AtomicU64,
+}
+
+impl Bitcask {
+    // append a record written on its own and hand it back with its position.
+    // with `SyncPolicy::Always` it is durable once this returns. writers call it under
+    // their shard lock to keep the log and the index in the same order, so only writers
+    // of different shards share a group
+    pub(crate) fn commit_record(&self, record: Record) -> Result<(Record, RecordPosition)> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.opts.sync_policy != SyncPolicy::Always {
+            let pos = self.append_record(&record)?;
+            return Ok((record, pos));
+        }
+
+        let (tx, rx) = bounded(1);
+        self.commit_queue.pending.lock().push((record, tx));
+
+        // the previous leader may have taken our record along with its group
+        let _leader = self.commit_queue.leader.lock();
+        if let Ok(res) = rx.try_recv() {
+            return res;
+        }
+
+        let (records, waiters): (Vec<Record>, Vec<_>) =
+            std::mem::take(&mut *self.commit_queue.pending.lock())
+                .into_iter()
+                .unzip();
+
+        match self.write_group(&records) {
+            Ok(positions) => {
+                for ((record, pos), tx) in records.into_iter().zip(positions).zip(waiters) {
+                    let _ = tx.send(Ok((record, pos)));
+                }
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                for tx in waiters {
+                    let _ = tx.send(Err(Error::GroupCommit(e.clone())));
+                }
+            }
+        }
+
+        // our record was in the group, unless the leader that took it panicked
+        rx.recv()
+            .unwrap_or_else(|_| Err(Error::Io(io::Error::other("group commit leader is gone"))))
+    }
+
+    // one write per data file and a single sync for the whole group
+    fn write_group(&self, records: &[Record]) -> Result<Vec<RecordPosition>> {
+        let mut active_file = self.active_file.write();
+
+        let mut positions = Vec::with_capacity(records.len());
+        let mut buf = Vec::new();
+        let mut hints = Vec::new();
+        for record in records {
+            let encoded = record.encode();
+            if active_file.write_offset as usize + buf.len() + encoded.len()
+                > self.opts.max_file_size
+            {
+                self.flush_group(&mut active_file, &mut buf, &mut hints)?;
+                self.rotate_active_file(&mut active_file, &mut self.old_files.write())?;
+            }
+
+            let pos = RecordPosition::new(
+                active_file.id,
+                active_file.write_offset + buf.len() as u64,
+                encoded.len() as u32,
+            );
+            hints.extend(record.to_hint(pos).encode());
+            buf.extend(encoded);
+            positions.push(pos);
+        }
+
+        self.flush_group(&mut active_file, &mut buf, &mut hints)?;
+        active_file.sync()?;
+        self.bytes_written.store(0, Ordering::SeqCst);
+        self.commit_queue.groups.fetch_add(1, Ordering::SeqCst);
+
+        Ok(positions)
+    }
+
+    fn flush_group(
+        &self,
+        active_file: &mut DataFile,
+        buf: &mut Vec<u8>,
+        hints: &mut Vec<u8>,
+    ) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let write_size = active_file.write(buf)?;
+        assert_eq!(buf.len(), write_size as usize);
+        buf.clear();
+
+        self.bytes_written
+            .fetch_add(write_size as usize, Ordering::SeqCst);
+        self.active_hints.lock().append(hints);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::options::BitcaskOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_group_commit() -> Result<()> {
+        let path = "/tmp/bitcask_group_commit";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 16 << 10,
+            sync_policy: SyncPolicy::Always,
+            ..Default::default()
+        };
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            let writers: Vec<_> = (0..8)
+                .map(|t| {
+                    let bitcask = Arc::clone(&bitcask);
+                    std::thread::spawn(move || -> Result<()> {
+                        for i in 0..200 {
+                            bitcask.put(format!("{}-{:04}", t, i), format!("{:032}", i))?;
+                        }
+                        for i in 0..50 {
+                            bitcask.delete(format!("{}-{:04}", t, i))?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap()?;
+            }
+
+            assert!(bitcask.stat()?.data_file_num > 1);
+            assert_eq!(8 * 150, bitcask.stat()?.key_num);
+            // dropped without close, every write is already on disk
+        }
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(8 * 150, bitcask.stat()?.key_num);
+        for t in 0..8 {
+            assert!(bitcask.get(format!("{}-{:04}", t, 49)).is_err());
+            for i in 50..200 {
+                let value = bitcask.get(format!("{}-{:04}", t, i))?;
+                assert_eq!(format!("{:032}", i).into_bytes(), value);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_commit_same_shard() -> Result<()> {
+        let path = "/tmp/bitcask_group_commit_same_shard";
+        let _ = std::fs::remove_dir_all(path);
+        let bitcask = Bitcask::open(BitcaskOptions {
+            db_path: path.into(),
+            sync_policy: SyncPolicy::Always,
+            index_num: 1,
+            ..Default::default()
+        })?;
+
+        // writers of one shard commit one at a time, each write is a group of its own
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let bitcask = Arc::clone(&bitcask);
+                std::thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        bitcask.put(format!("{}-{:04}", t, i), "v")?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+
+        assert_eq!(200, bitcask.stat()?.key_num);
+        assert_eq!(200, bitcask.commit_queue.groups.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+}
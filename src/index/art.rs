@@ -0,0 +1,772 @@
+use std::{cmp::Ordering, ops::Bound};
+
+use parking_lot::RwLock;
+
+use crate::{
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    key::Key,
+};
+
+use super::{IndexIterator, Indexer, KeyRange, SeekIndex, SeekIterator};
+
+// adaptive radix tree, inner nodes grow from 4 up to 256 children as needed
+#[derive(Default)]
+pub struct Art {
+    tree: RwLock<Tree>,
+}
+
+impl Art {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default)]
+struct Tree {
+    root: Option<Node>,
+    len: usize,
+}
+
+struct Leaf {
+    key: Key,
+    pos: RecordPosition,
+}
+
+enum Node {
+    Leaf(Leaf),
+    Inner(Box<Inner>),
+}
+
+struct Inner {
+    // bytes shared by every key below, compressed out of the path
+    prefix: Vec<u8>,
+    // key ending right after the prefix
+    end: Option<Leaf>,
+    children: Children,
+}
+
+enum Children {
+    // keys sorted, up to 4 and 16 children
+    Node4(Sorted),
+    Node16(Sorted),
+    // byte -> slot + 1, up to 48 children
+    Node48 {
+        slots: Box<[u8; 256]>,
+        nodes: Vec<Option<Node>>,
+        len: usize,
+    },
+    // indexed by byte
+    Node256 {
+        nodes: Vec<Option<Node>>,
+        len: usize,
+    },
+}
+
+#[derive(Default)]
+struct Sorted {
+    keys: Vec<u8>,
+    nodes: Vec<Node>,
+}
+
+impl Children {
+    fn len(&self) -> usize {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => sorted.keys.len(),
+            Children::Node48 { len, .. } | Children::Node256 { len, .. } => *len,
+        }
+    }
+
+    // smallest node type holding the sorted `entries`
+    fn from_entries(entries: Vec<(u8, Node)>) -> Self {
+        match entries.len() {
+            0..=4 => {
+                let (keys, nodes) = entries.into_iter().unzip();
+                Children::Node4(Sorted { keys, nodes })
+            }
+            5..=16 => {
+                let (keys, nodes) = entries.into_iter().unzip();
+                Children::Node16(Sorted { keys, nodes })
+            }
+            17..=48 => {
+                let mut slots = Box::new([0; 256]);
+                let len = entries.len();
+                let mut nodes: Vec<Option<Node>> = (0..48).map(|_| None).collect();
+                for (i, (byte, node)) in entries.into_iter().enumerate() {
+                    slots[byte as usize] = i as u8 + 1;
+                    nodes[i] = Some(node);
+                }
+                Children::Node48 { slots, nodes, len }
+            }
+            _ => {
+                let len = entries.len();
+                let mut nodes: Vec<Option<Node>> = (0..256).map(|_| None).collect();
+                for (byte, node) in entries {
+                    nodes[byte as usize] = Some(node);
+                }
+                Children::Node256 { nodes, len }
+            }
+        }
+    }
+
+    // children sorted by byte
+    fn into_entries(self) -> Vec<(u8, Node)> {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => {
+                sorted.keys.into_iter().zip(sorted.nodes).collect()
+            }
+            Children::Node48 {
+                slots, mut nodes, ..
+            } => (0..=255u8)
+                .filter(|byte| slots[*byte as usize] != 0)
+                .filter_map(|byte| {
+                    let node = nodes[slots[byte as usize] as usize - 1].take()?;
+                    Some((byte, node))
+                })
+                .collect(),
+            Children::Node256 { nodes, .. } => nodes
+                .into_iter()
+                .enumerate()
+                .filter_map(|(byte, node)| Some((byte as u8, node?)))
+                .collect(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Children::Node4(_) => 4,
+            Children::Node16(_) => 16,
+            Children::Node48 { .. } => 48,
+            Children::Node256 { .. } => 256,
+        }
+    }
+
+    fn get(&self, byte: u8) -> Option<&Node> {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => sorted
+                .keys
+                .binary_search(&byte)
+                .ok()
+                .map(|i| &sorted.nodes[i]),
+            Children::Node48 { slots, nodes, .. } => match slots[byte as usize] {
+                0 => None,
+                slot => nodes[slot as usize - 1].as_ref(),
+            },
+            Children::Node256 { nodes, .. } => nodes[byte as usize].as_ref(),
+        }
+    }
+
+    fn get_mut(&mut self, byte: u8) -> Option<&mut Node> {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => sorted
+                .keys
+                .binary_search(&byte)
+                .ok()
+                .map(|i| &mut sorted.nodes[i]),
+            Children::Node48 { slots, nodes, .. } => match slots[byte as usize] {
+                0 => None,
+                slot => nodes[slot as usize - 1].as_mut(),
+            },
+            Children::Node256 { nodes, .. } => nodes[byte as usize].as_mut(),
+        }
+    }
+
+    // `byte` must be absent
+    fn insert(&mut self, byte: u8, node: Node) {
+        if self.len() == self.capacity() {
+            let mut entries =
+                std::mem::replace(self, Children::Node4(Sorted::default())).into_entries();
+            let i = entries.partition_point(|(b, _)| *b < byte);
+            entries.insert(i, (byte, node));
+            *self = Children::from_entries(entries);
+            return;
+        }
+
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => {
+                let i = sorted.keys.partition_point(|b| *b < byte);
+                sorted.keys.insert(i, byte);
+                sorted.nodes.insert(i, node);
+            }
+            Children::Node48 { slots, nodes, len } => {
+                let slot = nodes.iter().position(|node| node.is_none()).unwrap();
+                nodes[slot] = Some(node);
+                slots[byte as usize] = slot as u8 + 1;
+                *len += 1;
+            }
+            Children::Node256 { nodes, len } => {
+                nodes[byte as usize] = Some(node);
+                *len += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, byte: u8) -> Option<Node> {
+        let node = match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => {
+                let i = sorted.keys.binary_search(&byte).ok()?;
+                sorted.keys.remove(i);
+                Some(sorted.nodes.remove(i))
+            }
+            Children::Node48 { slots, nodes, len } => {
+                let slot = std::mem::take(&mut slots[byte as usize]);
+                if slot == 0 {
+                    return None;
+                }
+                *len -= 1;
+                nodes[slot as usize - 1].take()
+            }
+            Children::Node256 { nodes, len } => {
+                let node = nodes[byte as usize].take()?;
+                *len -= 1;
+                Some(node)
+            }
+        };
+
+        // shrink well below the smaller capacity so that a node does not flap between sizes
+        let shrink = match self {
+            Children::Node16(_) => self.len() <= 3,
+            Children::Node48 { .. } => self.len() <= 12,
+            Children::Node256 { .. } => self.len() <= 36,
+            Children::Node4(_) => false,
+        };
+        if shrink {
+            let entries =
+                std::mem::replace(self, Children::Node4(Sorted::default())).into_entries();
+            *self = Children::from_entries(entries);
+        }
+
+        node
+    }
+
+    // child with the smallest byte > `byte`, or the smallest one at all
+    fn next_after(&self, byte: Option<u8>) -> Option<(u8, &Node)> {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => {
+                let i = match byte {
+                    Some(byte) => sorted.keys.partition_point(|b| *b <= byte),
+                    None => 0,
+                };
+                Some((*sorted.keys.get(i)?, &sorted.nodes[i]))
+            }
+            _ => {
+                let start = byte.map_or(0, |byte| byte as usize + 1);
+                (start..256).find_map(|b| Some((b as u8, self.get(b as u8)?)))
+            }
+        }
+    }
+
+    // child with the largest byte < `byte`, or the largest one at all
+    fn prev_before(&self, byte: Option<u8>) -> Option<(u8, &Node)> {
+        match self {
+            Children::Node4(sorted) | Children::Node16(sorted) => {
+                let i = match byte {
+                    Some(byte) => sorted.keys.partition_point(|b| *b < byte),
+                    None => sorted.keys.len(),
+                };
+                let i = i.checked_sub(1)?;
+                Some((sorted.keys[i], &sorted.nodes[i]))
+            }
+            _ => {
+                let end = byte.map_or(256, |byte| byte as usize);
+                (0..end)
+                    .rev()
+                    .find_map(|b| Some((b as u8, self.get(b as u8)?)))
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            prefix,
+            end: None,
+            children: Children::Node4(Sorted::default()),
+        }
+    }
+
+    // place a leaf whose key matches this node up to `depth`
+    fn add_leaf(&mut self, leaf: Leaf, depth: usize) {
+        match leaf.key.get(depth) {
+            Some(byte) => self.children.insert(*byte, Node::Leaf(leaf)),
+            None => self.end = Some(leaf),
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn min_leaf(node: &Node) -> &Leaf {
+    match node {
+        Node::Leaf(leaf) => leaf,
+        Node::Inner(inner) => match &inner.end {
+            Some(leaf) => leaf,
+            None => min_leaf(inner.children.next_after(None).unwrap().1),
+        },
+    }
+}
+
+fn max_leaf(node: &Node) -> &Leaf {
+    match node {
+        Node::Leaf(leaf) => leaf,
+        Node::Inner(inner) => match inner.children.prev_before(None) {
+            Some((_, child)) => max_leaf(child),
+            None => inner.end.as_ref().unwrap(),
+        },
+    }
+}
+
+impl Tree {
+    fn get(&self, key: &[u8]) -> Option<RecordPosition> {
+        let mut node = self.root.as_ref()?;
+        let mut depth = 0;
+
+        loop {
+            match node {
+                Node::Leaf(leaf) => return (leaf.key == key).then_some(leaf.pos),
+                Node::Inner(inner) => {
+                    if !key[depth..].starts_with(&inner.prefix) {
+                        return None;
+                    }
+                    depth += inner.prefix.len();
+
+                    if depth == key.len() {
+                        return inner.end.as_ref().map(|leaf| leaf.pos);
+                    }
+                    node = inner.children.get(key[depth])?;
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Key, pos: RecordPosition) -> Option<RecordPosition> {
+        let old = match &mut self.root {
+            Some(root) => insert(root, key, pos, 0),
+            None => {
+                self.root = Some(Node::Leaf(Leaf { key, pos }));
+                None
+            }
+        };
+
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<RecordPosition> {
+        let (old, empty) = remove(self.root.as_mut()?, key, 0);
+        if empty {
+            self.root = None;
+        }
+
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    // first leaf after `bound`
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> Option<&Leaf> {
+        let root = self.root.as_ref()?;
+
+        match bound {
+            Bound::Included(key) => seek_ge(root, key, true, 0),
+            Bound::Excluded(key) => seek_ge(root, key, false, 0),
+            Bound::Unbounded => Some(min_leaf(root)),
+        }
+    }
+
+    // last leaf before `bound`
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> Option<&Leaf> {
+        let root = self.root.as_ref()?;
+
+        match bound {
+            Bound::Included(key) => seek_le(root, key, true, 0),
+            Bound::Excluded(key) => seek_le(root, key, false, 0),
+            Bound::Unbounded => Some(max_leaf(root)),
+        }
+    }
+}
+
+// `node` is reached by the first `depth` bytes of `key`
+fn insert(node: &mut Node, key: Key, pos: RecordPosition, depth: usize) -> Option<RecordPosition> {
+    match node {
+        Node::Leaf(leaf) => {
+            if leaf.key == key {
+                return Some(std::mem::replace(&mut leaf.pos, pos));
+            }
+
+            // split the leaf into a node holding both keys
+            let common = common_prefix_len(&leaf.key[depth..], &key[depth..]);
+            let inner = Inner::new(key[depth..depth + common].to_vec());
+
+            let Node::Leaf(old) = std::mem::replace(node, Node::Inner(Box::new(inner))) else {
+                unreachable!()
+            };
+            if let Node::Inner(inner) = node {
+                inner.add_leaf(old, depth + common);
+                inner.add_leaf(Leaf { key, pos }, depth + common);
+            }
+            None
+        }
+        Node::Inner(inner) => {
+            let common = common_prefix_len(&inner.prefix, &key[depth..]);
+
+            if common < inner.prefix.len() {
+                // the key leaves the prefix, split it in front of the first different byte
+                let byte = inner.prefix[common];
+                let parent = Inner::new(inner.prefix[..common].to_vec());
+                inner.prefix.drain(..=common);
+
+                let old = std::mem::replace(node, Node::Inner(Box::new(parent)));
+                if let Node::Inner(parent) = node {
+                    parent.children.insert(byte, old);
+                    parent.add_leaf(Leaf { key, pos }, depth + common);
+                }
+                return None;
+            }
+
+            let depth = depth + common;
+            if depth == key.len() {
+                return match &mut inner.end {
+                    Some(leaf) => Some(std::mem::replace(&mut leaf.pos, pos)),
+                    None => {
+                        inner.end = Some(Leaf { key, pos });
+                        None
+                    }
+                };
+            }
+
+            let byte = key[depth];
+            match inner.children.get_mut(byte) {
+                Some(child) => insert(child, key, pos, depth + 1),
+                None => {
+                    inner.children.insert(byte, Node::Leaf(Leaf { key, pos }));
+                    None
+                }
+            }
+        }
+    }
+}
+
+// return the removed position and whether `node` has become empty
+fn remove(node: &mut Node, key: &[u8], depth: usize) -> (Option<RecordPosition>, bool) {
+    let Node::Inner(inner) = node else {
+        let Node::Leaf(leaf) = node else {
+            unreachable!()
+        };
+        return match leaf.key == key {
+            true => (Some(leaf.pos), true),
+            false => (None, false),
+        };
+    };
+
+    if !key[depth..].starts_with(&inner.prefix) {
+        return (None, false);
+    }
+    let depth = depth + inner.prefix.len();
+
+    let old = if depth == key.len() {
+        inner.end.take().map(|leaf| leaf.pos)
+    } else {
+        let byte = key[depth];
+        let Some(child) = inner.children.get_mut(byte) else {
+            return (None, false);
+        };
+
+        let (old, empty) = remove(child, key, depth + 1);
+        if empty {
+            inner.children.remove(byte);
+        }
+        old
+    };
+
+    if old.is_none() {
+        return (None, false);
+    }
+    (old, shrink(node))
+}
+
+// collapse a node left with a single key, return true if it holds nothing
+fn shrink(node: &mut Node) -> bool {
+    let Node::Inner(inner) = node else {
+        return false;
+    };
+
+    match (inner.end.is_some(), inner.children.len()) {
+        (false, 0) => true,
+        (true, 0) => {
+            *node = Node::Leaf(inner.end.take().unwrap());
+            false
+        }
+        (false, 1) => {
+            let byte = inner.children.next_after(None).unwrap().0;
+            let child = inner.children.remove(byte).unwrap();
+
+            *node = match child {
+                Node::Leaf(leaf) => Node::Leaf(leaf),
+                Node::Inner(mut child) => {
+                    // merge the prefixes with the byte between them
+                    let mut prefix = std::mem::take(&mut inner.prefix);
+                    prefix.push(byte);
+                    prefix.append(&mut child.prefix);
+                    child.prefix = prefix;
+                    Node::Inner(child)
+                }
+            };
+            false
+        }
+        _ => false,
+    }
+}
+
+// smallest leaf > `key` (>= if `inclusive`) below `node`
+fn seek_ge<'a>(node: &'a Node, key: &[u8], inclusive: bool, depth: usize) -> Option<&'a Leaf> {
+    let inner = match node {
+        Node::Leaf(leaf) => {
+            return match leaf.key.as_slice().cmp(key) {
+                Ordering::Greater => Some(leaf),
+                Ordering::Equal if inclusive => Some(leaf),
+                _ => None,
+            };
+        }
+        Node::Inner(inner) => inner,
+    };
+
+    let rest = &key[depth..];
+    let n = inner.prefix.len().min(rest.len());
+    match inner.prefix[..n].cmp(&rest[..n]) {
+        Ordering::Less => return None,
+        Ordering::Greater => return Some(min_leaf(node)),
+        Ordering::Equal => {}
+    }
+    // every key below extends `key`
+    if rest.len() < inner.prefix.len() {
+        return Some(min_leaf(node));
+    }
+
+    let depth = depth + inner.prefix.len();
+    if depth == key.len() {
+        if let Some(leaf) = inner.end.as_ref().filter(|_| inclusive) {
+            return Some(leaf);
+        }
+        return inner
+            .children
+            .next_after(None)
+            .map(|(_, child)| min_leaf(child));
+    }
+
+    let byte = key[depth];
+    if let Some(leaf) = inner
+        .children
+        .get(byte)
+        .and_then(|child| seek_ge(child, key, inclusive, depth + 1))
+    {
+        return Some(leaf);
+    }
+    inner
+        .children
+        .next_after(Some(byte))
+        .map(|(_, child)| min_leaf(child))
+}
+
+// largest leaf < `key` (<= if `inclusive`) below `node`
+fn seek_le<'a>(node: &'a Node, key: &[u8], inclusive: bool, depth: usize) -> Option<&'a Leaf> {
+    let inner = match node {
+        Node::Leaf(leaf) => {
+            return match leaf.key.as_slice().cmp(key) {
+                Ordering::Less => Some(leaf),
+                Ordering::Equal if inclusive => Some(leaf),
+                _ => None,
+            };
+        }
+        Node::Inner(inner) => inner,
+    };
+
+    let rest = &key[depth..];
+    let n = inner.prefix.len().min(rest.len());
+    match inner.prefix[..n].cmp(&rest[..n]) {
+        Ordering::Less => return Some(max_leaf(node)),
+        Ordering::Greater => return None,
+        Ordering::Equal => {}
+    }
+    // every key below extends `key`
+    if rest.len() < inner.prefix.len() {
+        return None;
+    }
+
+    let depth = depth + inner.prefix.len();
+    if depth == key.len() {
+        return inner.end.as_ref().filter(|_| inclusive);
+    }
+
+    let byte = key[depth];
+    if let Some(leaf) = inner
+        .children
+        .get(byte)
+        .and_then(|child| seek_le(child, key, inclusive, depth + 1))
+    {
+        return Some(leaf);
+    }
+    match inner.children.prev_before(Some(byte)) {
+        Some((_, child)) => Some(max_leaf(child)),
+        // the end key is a prefix of `key`
+        None => inner.end.as_ref(),
+    }
+}
+
+impl Indexer for Art {
+    fn put(&self, key: Key, pos: RecordPosition) -> Result<Option<RecordPosition>> {
+        Ok(self.tree.write().insert(key, pos))
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RecordPosition> {
+        self.tree.read().get(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<RecordPosition> {
+        self.tree.write().remove(key).ok_or(Error::KeyNotFound)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool {
+        let mut tree = self.tree.write();
+
+        if tree.get(key) != Some(expected) {
+            return false;
+        }
+        match pos {
+            Some(pos) => tree.insert(key.to_vec(), pos),
+            None => tree.remove(key),
+        };
+        true
+    }
+
+    fn exits(&self, key: &[u8]) -> bool {
+        self.tree.read().get(key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tree.read().len == 0
+    }
+
+    fn len(&self) -> usize {
+        self.tree.read().len
+    }
+
+    fn iterator(&self, range: KeyRange, reverse: bool) -> Result<Box<dyn IndexIterator + '_>> {
+        Ok(Box::new(SeekIterator::new(self, range, reverse)))
+    }
+}
+
+impl SeekIndex for Art {
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        self.tree
+            .read()
+            .lower_bound(bound)
+            .map(|leaf| (leaf.key.clone(), leaf.pos))
+    }
+
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        self.tree
+            .read()
+            .upper_bound(bound)
+            .map(|leaf| (leaf.key.clone(), leaf.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_key(rng: &mut StdRng) -> Key {
+        // few distinct bytes so that keys share prefixes and nodes fill up
+        let len = rng.gen_range(0..6);
+        (0..len)
+            .map(|_| match rng.gen_bool(0.5) {
+                true => rng.gen_range(0..4),
+                false => rng.gen(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_art_against_btree_map() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut tree = Tree::default();
+        let mut expected = BTreeMap::new();
+
+        for i in 0..20000u64 {
+            let key = random_key(&mut rng);
+            let pos = RecordPosition::new(0, i, 0);
+
+            if rng.gen_bool(0.6) {
+                assert_eq!(expected.insert(key.clone(), pos), tree.insert(key, pos));
+            } else {
+                assert_eq!(expected.remove(&key), tree.remove(&key));
+            }
+            assert_eq!(expected.len(), tree.len);
+
+            let probe = random_key(&mut rng);
+            assert_eq!(expected.get(&probe).copied(), tree.get(&probe));
+
+            for bound in [
+                Bound::Included(probe.as_slice()),
+                Bound::Excluded(probe.as_slice()),
+                Bound::Unbounded,
+            ] {
+                let ge = expected.range::<[u8], _>((bound, Bound::Unbounded)).next();
+                let le = expected
+                    .range::<[u8], _>((Bound::Unbounded, bound))
+                    .next_back();
+
+                assert_eq!(
+                    ge.map(|(key, _)| key),
+                    tree.lower_bound(bound).map(|leaf| &leaf.key)
+                );
+                assert_eq!(
+                    le.map(|(key, _)| key),
+                    tree.upper_bound(bound).map(|leaf| &leaf.key)
+                );
+            }
+        }
+
+        for key in expected.keys() {
+            assert!(tree.remove(key).is_some());
+        }
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn test_art_iterator() -> Result<()> {
+        let index = Art::new();
+        for key in ["", "a", "ab", "abc", "b", "ba"] {
+            index.put(key.into(), RecordPosition::new(0, 0, 0))?;
+        }
+
+        let mut iter = index.iterator(
+            (Bound::Included("a".into()), Bound::Excluded("b".into())),
+            true,
+        )?;
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(String::from_utf8(key).unwrap());
+        }
+        assert_eq!(vec!["abc", "ab", "a"], keys);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1021 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    ops::Bound,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+use bytes::{Buf, BufMut};
+use parking_lot::Mutex;
+
+use crate::{
+    consts::BPTREE_INDEX_FILE_NAME,
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    key::{Key, MAX_KEY_SIZE},
+};
+
+use super::{IndexIterator, IndexState, Indexer, KeyRange, SeekIndex, SeekIterator};
+
+const PAGE_SIZE: usize = 4096;
+// decoded pages kept in memory, the rest of the tree stays on disk
+const PAGE_CACHE_CAPACITY: usize = 1024;
+
+const MAGIC: &[u8; 8] = b"BCBPTREE";
+const HEADER_SIZE: usize = 8 + 1 + 4 + 4 + 4 + 8 + 8;
+const SHARD_META_SIZE: usize = 4 + 8;
+
+const LEAF_PAGE: u8 = 1;
+const INTERNAL_PAGE: u8 = 2;
+const FREE_PAGE: u8 = 3;
+const POS_SIZE: usize = 16;
+
+// one shard of the b+tree index, all shards share a single file
+pub struct BPlusTree {
+    pager: Arc<Mutex<Pager>>,
+    shard: usize,
+}
+
+impl BPlusTree {
+    // open the index file under `dir`. it is reset if it was not closed cleanly
+    // with the same number of shards, the caller rebuilds it from the data files
+    pub fn open(dir: impl AsRef<Path>, shard_num: usize) -> Result<Vec<Self>> {
+        let pager = Arc::new(Mutex::new(Pager::open(
+            dir.as_ref().join(BPTREE_INDEX_FILE_NAME),
+            shard_num,
+        )?));
+
+        Ok((0..shard_num)
+            .map(|shard| Self {
+                pager: pager.clone(),
+                shard,
+            })
+            .collect())
+    }
+}
+
+impl Indexer for BPlusTree {
+    fn put(&self, key: Key, pos: RecordPosition) -> Result<Option<RecordPosition>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge {
+                size: key.len(),
+                max: MAX_KEY_SIZE,
+            });
+        }
+
+        self.pager.lock().put(self.shard, key, pos)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RecordPosition> {
+        self.pager.lock().get(self.shard, key).unwrap_or_else(|e| {
+            log::error!("b+tree index read error: {}", e);
+            None
+        })
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<RecordPosition> {
+        self.pager
+            .lock()
+            .delete(self.shard, key)?
+            .ok_or(Error::KeyNotFound)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool {
+        let mut pager = self.pager.lock();
+
+        let res = pager.get(self.shard, key).and_then(|cur| {
+            if cur != Some(expected) {
+                return Ok(false);
+            }
+            match pos {
+                Some(pos) => pager.put(self.shard, key.to_vec(), pos)?,
+                None => pager.delete(self.shard, key)?,
+            };
+            Ok(true)
+        });
+
+        res.unwrap_or_else(|e| {
+            log::error!("b+tree index write error: {}", e);
+            false
+        })
+    }
+
+    fn exits(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn len(&self) -> usize {
+        self.pager.lock().header.shards[self.shard].len as usize
+    }
+
+    fn iterator(&self, range: KeyRange, reverse: bool) -> Result<Box<dyn IndexIterator + '_>> {
+        Ok(Box::new(SeekIterator::new(self, range, reverse)))
+    }
+
+    fn saved_state(&self) -> Option<IndexState> {
+        self.pager.lock().saved_state
+    }
+
+    fn persist(&self, state: IndexState) -> Result<()> {
+        self.pager.lock().flush(state)
+    }
+}
+
+impl SeekIndex for BPlusTree {
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        let mut pager = self.pager.lock();
+        let root = pager.header.shards[self.shard].root;
+
+        pager.lower_bound(root, bound).unwrap_or_else(|e| {
+            log::error!("b+tree index read error: {}", e);
+            None
+        })
+    }
+
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        let mut pager = self.pager.lock();
+        let root = pager.header.shards[self.shard].root;
+
+        pager.upper_bound(root, bound).unwrap_or_else(|e| {
+            log::error!("b+tree index read error: {}", e);
+            None
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Leaf {
+        keys: Vec<Key>,
+        positions: Vec<RecordPosition>,
+    },
+    // `children[i]` holds the keys in [keys[i - 1], keys[i])
+    Internal {
+        keys: Vec<Key>,
+        children: Vec<u32>,
+    },
+}
+
+impl Node {
+    fn empty_leaf() -> Self {
+        Node::Leaf {
+            keys: Vec::new(),
+            positions: Vec::new(),
+        }
+    }
+
+    fn encoded_size(&self) -> usize {
+        match self {
+            Node::Leaf { keys, .. } => {
+                3 + keys
+                    .iter()
+                    .map(|key| 2 + key.len() + POS_SIZE)
+                    .sum::<usize>()
+            }
+            Node::Internal { keys, .. } => {
+                3 + 4 + keys.iter().map(|key| 2 + key.len() + 4).sum::<usize>()
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+
+        match self {
+            Node::Leaf { keys, positions } => {
+                buf.put_u8(LEAF_PAGE);
+                buf.put_u16(keys.len() as u16);
+                for (key, pos) in keys.iter().zip(positions) {
+                    buf.put_u16(key.len() as u16);
+                    buf.put_slice(key);
+                    buf.put_slice(&pos.encode());
+                }
+            }
+            Node::Internal { keys, children } => {
+                buf.put_u8(INTERNAL_PAGE);
+                buf.put_u16(keys.len() as u16);
+                buf.put_u32(children[0]);
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    buf.put_u16(key.len() as u16);
+                    buf.put_slice(key);
+                    buf.put_u32(*child);
+                }
+            }
+        }
+
+        buf.resize(PAGE_SIZE, 0);
+        buf
+    }
+
+    // every read is checked, a broken page is an error and never a panic
+    fn decode(page: &[u8]) -> Result<Self> {
+        let mut data = page;
+        let page_type = take(&mut data, 1)?[0];
+        let count = take_u16(&mut data)? as usize;
+
+        let mut keys = Vec::with_capacity(count);
+        match page_type {
+            LEAF_PAGE => {
+                let mut positions = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_len = take_u16(&mut data)? as usize;
+                    keys.push(take(&mut data, key_len)?.to_vec());
+                    positions.push(RecordPosition::decode(take(&mut data, POS_SIZE)?));
+                }
+                Ok(Node::Leaf { keys, positions })
+            }
+            INTERNAL_PAGE => {
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(take_u32(&mut data)?);
+                for _ in 0..count {
+                    let key_len = take_u16(&mut data)? as usize;
+                    keys.push(take(&mut data, key_len)?.to_vec());
+                    children.push(take_u32(&mut data)?);
+                }
+                Ok(Node::Internal { keys, children })
+            }
+            _ => Err(Error::IndexBroken("b+tree page")),
+        }
+    }
+
+    // split off the upper half, return it with the smallest key it covers
+    fn split(&mut self) -> (Key, Node) {
+        match self {
+            Node::Leaf { keys, positions } => {
+                let mid = split_point(keys, POS_SIZE).clamp(1, keys.len() - 1);
+                let right_keys = keys.split_off(mid);
+                let right_positions = positions.split_off(mid);
+
+                (
+                    right_keys[0].clone(),
+                    Node::Leaf {
+                        keys: right_keys,
+                        positions: right_positions,
+                    },
+                )
+            }
+            Node::Internal { keys, children } => {
+                // the middle key moves up to the parent
+                let mid = split_point(keys, 4).clamp(1, keys.len() - 2);
+                let right_keys = keys.split_off(mid + 1);
+                let sep = keys.pop().unwrap();
+                let right_children = children.split_off(mid + 1);
+
+                (
+                    sep,
+                    Node::Internal {
+                        keys: right_keys,
+                        children: right_children,
+                    },
+                )
+            }
+        }
+    }
+}
+
+// the next `len` bytes of a page
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(Error::IndexBroken("b+tree page"));
+    }
+
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Ok(head)
+}
+
+fn take_u16(data: &mut &[u8]) -> Result<u16> {
+    Ok(u16::from_be_bytes(take(data, 2)?.try_into().unwrap()))
+}
+
+fn take_u32(data: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(take(data, 4)?.try_into().unwrap()))
+}
+
+// index where the encoded entries reach half of their total size
+fn split_point(keys: &[Key], entry_extra: usize) -> usize {
+    let total: usize = keys.iter().map(|key| 2 + key.len() + entry_extra).sum();
+
+    let mut size = 0;
+    keys.iter()
+        .position(|key| {
+            size += 2 + key.len() + entry_extra;
+            size >= total / 2
+        })
+        .unwrap_or(keys.len() / 2)
+}
+
+struct ShardMeta {
+    root: u32,
+    len: u64,
+}
+
+// first page of the file
+struct Header {
+    // the tree matches the data files as of `state`
+    clean: bool,
+    page_count: u32,
+    // head of the free page chain, 0 if empty
+    free_head: u32,
+    state: IndexState,
+    shards: Vec<ShardMeta>,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+
+        buf.put_slice(MAGIC);
+        buf.put_u8(self.clean as u8);
+        buf.put_u32(self.shards.len() as u32);
+        buf.put_u32(self.page_count);
+        buf.put_u32(self.free_head);
+        buf.put_u64(self.state.batch_seq);
+        buf.put_u64(self.state.reclaimable);
+        for shard in self.shards.iter() {
+            buf.put_u32(shard.root);
+            buf.put_u64(shard.len);
+        }
+
+        buf.resize(PAGE_SIZE, 0);
+        buf
+    }
+
+    fn decode(page: &[u8]) -> Option<Self> {
+        let mut data = page;
+        if &data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        data.advance(MAGIC.len());
+
+        let clean = data.get_u8() == 1;
+        let shard_num = data.get_u32() as usize;
+        let page_count = data.get_u32();
+        let free_head = data.get_u32();
+        let state = IndexState {
+            batch_seq: data.get_u64(),
+            reclaimable: data.get_u64(),
+        };
+
+        if HEADER_SIZE + shard_num * SHARD_META_SIZE > PAGE_SIZE {
+            return None;
+        }
+        let shards = (0..shard_num)
+            .map(|_| ShardMeta {
+                root: data.get_u32(),
+                len: data.get_u64(),
+            })
+            .collect();
+
+        Some(Self {
+            clean,
+            page_count,
+            free_head,
+            state,
+            shards,
+        })
+    }
+}
+
+// separator key and page of the node split off to the right
+type Split = (Key, u32);
+
+struct CachedPage {
+    node: Arc<Node>,
+    dirty: bool,
+    last_used: u64,
+}
+
+// page file with a bounded cache of decoded nodes
+struct Pager {
+    file: File,
+    header: Header,
+    cache: HashMap<u32, CachedPage>,
+    tick: u64,
+    // the header on disk still claims the tree is clean
+    clean_on_disk: bool,
+    // state of the last clean close, if the tree can be trusted
+    saved_state: Option<IndexState>,
+}
+
+impl Pager {
+    fn open(path: impl AsRef<Path>, shard_num: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut page = vec![0; PAGE_SIZE];
+        let header = match file.read_exact_at(&mut page, 0) {
+            Ok(_) => Header::decode(&page),
+            Err(_) => None,
+        };
+
+        let mut pager = Self {
+            file,
+            header: Header {
+                clean: false,
+                page_count: 1,
+                free_head: 0,
+                state: IndexState::default(),
+                shards: Vec::new(),
+            },
+            cache: HashMap::new(),
+            tick: 0,
+            clean_on_disk: false,
+            saved_state: None,
+        };
+
+        match header {
+            Some(header) if header.clean && header.shards.len() == shard_num => {
+                let state = header.state;
+                pager.header = header;
+
+                // a broken tree is dropped, the caller rebuilds it from the data files
+                match pager.check() {
+                    Ok(()) => {
+                        pager.saved_state = Some(state);
+                        pager.clean_on_disk = true;
+                    }
+                    Err(e) => {
+                        log::warn!("b+tree index is broken, rebuild it: {}", e);
+                        pager.reset(shard_num)?;
+                    }
+                }
+            }
+            _ => pager.reset(shard_num)?,
+        }
+
+        // any change from now on makes the file stale until the next flush
+        pager.mark_dirty()?;
+
+        Ok(pager)
+    }
+
+    // decode every page the roots reach, without caching them
+    fn check(&self) -> Result<()> {
+        let mut visited = HashSet::new();
+        let mut pending: Vec<u32> = self.header.shards.iter().map(|shard| shard.root).collect();
+
+        let mut buf = vec![0; PAGE_SIZE];
+        while let Some(id) = pending.pop() {
+            // page 0 holds the header, a page is never reached twice
+            if id == 0 || id >= self.header.page_count || !visited.insert(id) {
+                return Err(Error::IndexBroken("b+tree page id"));
+            }
+
+            self.file
+                .read_exact_at(&mut buf, id as u64 * PAGE_SIZE as u64)?;
+            if let Node::Internal { children, .. } = Node::decode(&buf)? {
+                pending.extend(children);
+            }
+        }
+
+        Ok(())
+    }
+
+    // drop every tree and start with empty roots
+    fn reset(&mut self, shard_num: usize) -> Result<()> {
+        if shard_num * SHARD_META_SIZE + HEADER_SIZE > PAGE_SIZE {
+            return Err(Error::InvalidOptions(
+                "too many shards for the b+tree index",
+            ));
+        }
+
+        self.file.set_len(0)?;
+        self.cache.clear();
+        self.header = Header {
+            clean: false,
+            page_count: 1,
+            free_head: 0,
+            state: IndexState::default(),
+            shards: Vec::with_capacity(shard_num),
+        };
+
+        for _ in 0..shard_num {
+            let root = self.alloc()?;
+            self.write(root, Node::empty_leaf());
+            self.header.shards.push(ShardMeta { root, len: 0 });
+        }
+
+        self.file.write_all_at(&self.header.encode(), 0)?;
+        Ok(())
+    }
+
+    fn mark_dirty(&mut self) -> Result<()> {
+        if !self.clean_on_disk {
+            return Ok(());
+        }
+
+        self.header.clean = false;
+        self.file.write_all_at(&self.header.encode(), 0)?;
+        self.file.sync_all()?;
+        self.clean_on_disk = false;
+
+        Ok(())
+    }
+
+    // write all pages back and mark the file clean with `state`
+    fn flush(&mut self, state: IndexState) -> Result<()> {
+        for (id, page) in self.cache.iter_mut().filter(|(_, page)| page.dirty) {
+            self.file
+                .write_all_at(&page.node.encode(), *id as u64 * PAGE_SIZE as u64)?;
+            page.dirty = false;
+        }
+        self.file.sync_all()?;
+
+        self.header.clean = true;
+        self.header.state = state;
+        self.file.write_all_at(&self.header.encode(), 0)?;
+        self.file.sync_all()?;
+        self.clean_on_disk = true;
+
+        Ok(())
+    }
+
+    fn node(&mut self, id: u32) -> Result<Arc<Node>> {
+        self.tick += 1;
+
+        if let Some(page) = self.cache.get_mut(&id) {
+            page.last_used = self.tick;
+            return Ok(page.node.clone());
+        }
+
+        let mut buf = vec![0; PAGE_SIZE];
+        self.file
+            .read_exact_at(&mut buf, id as u64 * PAGE_SIZE as u64)?;
+        let node = Arc::new(Node::decode(&buf)?);
+
+        self.cache.insert(
+            id,
+            CachedPage {
+                node: node.clone(),
+                dirty: false,
+                last_used: self.tick,
+            },
+        );
+        Ok(node)
+    }
+
+    fn write(&mut self, id: u32, node: Node) {
+        self.tick += 1;
+
+        self.cache.insert(
+            id,
+            CachedPage {
+                node: Arc::new(node),
+                dirty: true,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    // drop the least recently used quarter of the cache once it is full
+    fn evict(&mut self) -> Result<()> {
+        if self.cache.len() <= PAGE_CACHE_CAPACITY {
+            return Ok(());
+        }
+
+        let mut ticks: Vec<u64> = self.cache.values().map(|page| page.last_used).collect();
+        ticks.sort_unstable();
+        let threshold = ticks[ticks.len() / 4];
+
+        let evicted: Vec<u32> = self
+            .cache
+            .iter()
+            .filter(|(_, page)| page.last_used < threshold)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in evicted {
+            let page = self.cache.remove(&id).unwrap();
+            if page.dirty {
+                self.file
+                    .write_all_at(&page.node.encode(), id as u64 * PAGE_SIZE as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn alloc(&mut self) -> Result<u32> {
+        if self.header.free_head == 0 {
+            self.header.page_count += 1;
+            return Ok(self.header.page_count - 1);
+        }
+
+        let id = self.header.free_head;
+        let mut buf = [0; 5];
+        self.file
+            .read_exact_at(&mut buf, id as u64 * PAGE_SIZE as u64)?;
+        if buf[0] != FREE_PAGE {
+            return Err(Error::IndexBroken("b+tree free list"));
+        }
+        self.header.free_head = u32::from_be_bytes(buf[1..].try_into().unwrap());
+
+        Ok(id)
+    }
+
+    fn free(&mut self, id: u32) -> Result<()> {
+        self.cache.remove(&id);
+
+        let mut buf = vec![FREE_PAGE];
+        buf.put_u32(self.header.free_head);
+        self.file.write_all_at(&buf, id as u64 * PAGE_SIZE as u64)?;
+        self.header.free_head = id;
+
+        Ok(())
+    }
+
+    fn get(&mut self, shard: usize, key: &[u8]) -> Result<Option<RecordPosition>> {
+        let mut id = self.header.shards[shard].root;
+
+        loop {
+            match &*self.node(id)? {
+                Node::Leaf { keys, positions } => {
+                    return Ok(keys
+                        .binary_search_by(|k| k.as_slice().cmp(key))
+                        .ok()
+                        .map(|i| positions[i]));
+                }
+                Node::Internal { keys, children } => {
+                    id = children[keys.partition_point(|k| k.as_slice() <= key)];
+                }
+            }
+        }
+    }
+
+    fn put(
+        &mut self,
+        shard: usize,
+        key: Key,
+        pos: RecordPosition,
+    ) -> Result<Option<RecordPosition>> {
+        self.mark_dirty()?;
+
+        let root = self.header.shards[shard].root;
+        let (old, split) = self.insert(root, key, pos)?;
+
+        // grow a new root above the split one
+        if let Some((sep, right)) = split {
+            let id = self.alloc()?;
+            self.write(
+                id,
+                Node::Internal {
+                    keys: vec![sep],
+                    children: vec![root, right],
+                },
+            );
+            self.header.shards[shard].root = id;
+        }
+
+        if old.is_none() {
+            self.header.shards[shard].len += 1;
+        }
+        self.evict()?;
+
+        Ok(old)
+    }
+
+    // return the replaced position and the split off right node if `id` overflowed
+    fn insert(
+        &mut self,
+        id: u32,
+        key: Key,
+        pos: RecordPosition,
+    ) -> Result<(Option<RecordPosition>, Option<Split>)> {
+        let mut node = Node::clone(&*self.node(id)?);
+
+        let old = match &mut node {
+            Node::Leaf { keys, positions } => match keys.binary_search(&key) {
+                Ok(i) => Some(std::mem::replace(&mut positions[i], pos)),
+                Err(i) => {
+                    keys.insert(i, key);
+                    positions.insert(i, pos);
+                    None
+                }
+            },
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|k| *k <= key);
+                let (old, split) = self.insert(children[i], key, pos)?;
+
+                let Some((sep, right)) = split else {
+                    return Ok((old, None));
+                };
+                keys.insert(i, sep);
+                children.insert(i + 1, right);
+                old
+            }
+        };
+
+        let mut split = None;
+        if node.encoded_size() > PAGE_SIZE {
+            let (sep, right) = node.split();
+            let right_id = self.alloc()?;
+            self.write(right_id, right);
+            split = Some((sep, right_id));
+        }
+        self.write(id, node);
+
+        Ok((old, split))
+    }
+
+    fn delete(&mut self, shard: usize, key: &[u8]) -> Result<Option<RecordPosition>> {
+        self.mark_dirty()?;
+
+        let mut root = self.header.shards[shard].root;
+        let (old, empty) = self.remove(root, key)?;
+        if old.is_none() {
+            return Ok(None);
+        }
+
+        if empty {
+            self.write(root, Node::empty_leaf());
+        }
+
+        // shorten the tree while the root has a single child
+        while let Node::Internal { children, .. } = &*self.node(root)? {
+            if children.len() > 1 {
+                break;
+            }
+            let child = children[0];
+            self.free(root)?;
+            root = child;
+        }
+
+        self.header.shards[shard].root = root;
+        self.header.shards[shard].len -= 1;
+        self.evict()?;
+
+        Ok(old)
+    }
+
+    // return the removed position and whether `id` is left empty,
+    // empty nodes are freed by their parent so that every other node holds a key
+    fn remove(&mut self, id: u32, key: &[u8]) -> Result<(Option<RecordPosition>, bool)> {
+        let mut node = Node::clone(&*self.node(id)?);
+
+        let old = match &mut node {
+            Node::Leaf { keys, positions } => {
+                let Ok(i) = keys.binary_search_by(|k| k.as_slice().cmp(key)) else {
+                    return Ok((None, false));
+                };
+                keys.remove(i);
+                Some(positions.remove(i))
+            }
+            Node::Internal { keys, children } => {
+                let i = keys.partition_point(|k| k.as_slice() <= key);
+                let (old, empty) = self.remove(children[i], key)?;
+                if !empty {
+                    return Ok((old, false));
+                }
+
+                self.free(children[i])?;
+                children.remove(i);
+                if !keys.is_empty() {
+                    keys.remove(i.saturating_sub(1));
+                }
+                old
+            }
+        };
+
+        let empty = match &node {
+            Node::Leaf { keys, .. } => keys.is_empty(),
+            Node::Internal { children, .. } => children.is_empty(),
+        };
+        self.write(id, node);
+
+        Ok((old, empty))
+    }
+
+    // first entry after `bound` below `id`
+    fn lower_bound(
+        &mut self,
+        id: u32,
+        bound: Bound<&[u8]>,
+    ) -> Result<Option<(Key, RecordPosition)>> {
+        match &*self.node(id)? {
+            Node::Leaf { keys, positions } => {
+                let i = match bound {
+                    Bound::Included(key) => keys.partition_point(|k| k.as_slice() < key),
+                    Bound::Excluded(key) => keys.partition_point(|k| k.as_slice() <= key),
+                    Bound::Unbounded => 0,
+                };
+                Ok(keys.get(i).map(|key| (key.clone(), positions[i])))
+            }
+            Node::Internal { keys, children } => {
+                let i = match bound {
+                    Bound::Included(key) | Bound::Excluded(key) => {
+                        keys.partition_point(|k| k.as_slice() <= key)
+                    }
+                    Bound::Unbounded => 0,
+                };
+
+                if let Some(entry) = self.lower_bound(children[i], bound)? {
+                    return Ok(Some(entry));
+                }
+                match children.get(i + 1) {
+                    Some(next) => self.lower_bound(*next, Bound::Unbounded),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    // last entry before `bound` below `id`
+    fn upper_bound(
+        &mut self,
+        id: u32,
+        bound: Bound<&[u8]>,
+    ) -> Result<Option<(Key, RecordPosition)>> {
+        match &*self.node(id)? {
+            Node::Leaf { keys, positions } => {
+                let i = match bound {
+                    Bound::Included(key) => keys.partition_point(|k| k.as_slice() <= key),
+                    Bound::Excluded(key) => keys.partition_point(|k| k.as_slice() < key),
+                    Bound::Unbounded => keys.len(),
+                };
+                Ok(i.checked_sub(1).map(|i| (keys[i].clone(), positions[i])))
+            }
+            Node::Internal { keys, children } => {
+                let i = match bound {
+                    Bound::Included(key) => keys.partition_point(|k| k.as_slice() <= key),
+                    Bound::Excluded(key) => keys.partition_point(|k| k.as_slice() < key),
+                    Bound::Unbounded => children.len() - 1,
+                };
+
+                if let Some(entry) = self.upper_bound(children[i], bound)? {
+                    return Ok(Some(entry));
+                }
+                match i.checked_sub(1) {
+                    Some(prev) => self.upper_bound(children[prev], Bound::Unbounded),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn open(path: &str, shard_num: usize) -> Result<Vec<BPlusTree>> {
+        std::fs::create_dir_all(path)?;
+        BPlusTree::open(path, shard_num)
+    }
+
+    #[test]
+    fn test_bptree_against_btree_map() -> Result<()> {
+        let path = "/tmp/bitcask_bptree_random";
+        let _ = std::fs::remove_dir_all(path);
+        let tree = open(path, 1)?.remove(0);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut expected = BTreeMap::new();
+
+        // long keys so that the tree grows several levels and pages get evicted
+        let random_key = |rng: &mut StdRng| -> Key {
+            let mut key = format!("{:05}", rng.gen_range(0..8000)).into_bytes();
+            key.resize(rng.gen_range(5..200), b'x');
+            key
+        };
+
+        for i in 0..30000u64 {
+            let key = random_key(&mut rng);
+            let pos = RecordPosition::new(0, i, 0);
+
+            if rng.gen_bool(0.7) {
+                assert_eq!(expected.insert(key.clone(), pos), tree.put(key, pos)?);
+            } else {
+                assert_eq!(expected.remove(&key), tree.delete(&key).ok());
+            }
+
+            if i % 16 == 0 {
+                let probe = random_key(&mut rng);
+                assert_eq!(expected.get(&probe).copied(), tree.get(&probe));
+
+                let bound = Bound::Excluded(probe.as_slice());
+                let ge = expected.range::<[u8], _>((bound, Bound::Unbounded)).next();
+                let le = expected
+                    .range::<[u8], _>((Bound::Unbounded, bound))
+                    .next_back();
+                assert_eq!(ge.map(|(k, p)| (k.clone(), *p)), tree.lower_bound(bound));
+                assert_eq!(le.map(|(k, p)| (k.clone(), *p)), tree.upper_bound(bound));
+            }
+        }
+        assert_eq!(expected.len(), tree.len());
+
+        let mut iter = tree.iterator((Bound::Unbounded, Bound::Unbounded), true)?;
+        for (key, pos) in expected.iter().rev() {
+            assert_eq!(Some((key.clone(), *pos)), iter.next());
+        }
+        assert_eq!(None, iter.next());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bptree_reopen() -> Result<()> {
+        let path = "/tmp/bitcask_bptree_reopen";
+        let _ = std::fs::remove_dir_all(path);
+
+        let state = IndexState {
+            batch_seq: 3,
+            reclaimable: 100,
+        };
+        {
+            let trees = open(path, 2)?;
+            assert!(trees[0].saved_state().is_none());
+
+            for i in 0..5000u32 {
+                let key = format!("{:05}", i).into_bytes();
+                trees[i as usize % 2].put(key, RecordPosition::new(i, 0, 0))?;
+            }
+            trees[0].persist(state)?;
+        }
+
+        // a clean file is trusted once, until it is flushed again
+        let trees = open(path, 2)?;
+        assert_eq!(Some(state), trees[0].saved_state());
+        assert_eq!(2500, trees[1].len());
+        assert_eq!(
+            Some(RecordPosition::new(4999, 0, 0)),
+            trees[1].get(b"04999")
+        );
+        drop(trees);
+
+        let trees = open(path, 2)?;
+        assert!(trees[0].saved_state().is_none());
+        assert!(trees[0].is_empty());
+        trees[0].persist(state)?;
+        drop(trees);
+
+        // a different shard number resets the file
+        let trees = open(path, 3)?;
+        assert!(trees[0].saved_state().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bptree_broken_page() -> Result<()> {
+        let path = "/tmp/bitcask_bptree_broken";
+        let _ = std::fs::remove_dir_all(path);
+        let file = Path::new(path).join(BPTREE_INDEX_FILE_NAME);
+
+        let persist = || -> Result<()> {
+            let trees = open(path, 1)?;
+            for i in 0..5000u32 {
+                let key = format!("{:05}", i).into_bytes();
+                trees[0].put(key, RecordPosition::new(i, 0, 0))?;
+            }
+            trees[0].persist(IndexState::default())
+        };
+
+        // a leaf claiming more entries than fit in the page
+        persist()?;
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = LEAF_PAGE;
+        page[1..3].copy_from_slice(&u16::MAX.to_be_bytes());
+        for id in 1..3u64 {
+            OpenOptions::new()
+                .write(true)
+                .open(&file)?
+                .write_all_at(&page, id * PAGE_SIZE as u64)?;
+        }
+        let trees = open(path, 1)?;
+        assert!(trees[0].saved_state().is_none());
+        assert!(trees[0].is_empty());
+        drop(trees);
+
+        // pages cut off at the end of the file
+        persist()?;
+        OpenOptions::new()
+            .write(true)
+            .open(&file)?
+            .set_len(PAGE_SIZE as u64 * 2)?;
+        let trees = open(path, 1)?;
+        assert!(trees[0].saved_state().is_none());
+        assert!(trees[0].is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use parking_lot::RwLock;
+
+use crate::{
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    key::Key,
+};
+
+use super::{IndexIterator, Indexer, KeyRange, SeekIndex, SeekIterator};
+
+#[derive(Default)]
+pub struct BTree {
+    map: RwLock<BTreeMap<Key, RecordPosition>>,
+}
+
+impl BTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Indexer for BTree {
+    fn put(&self, key: Key, pos: RecordPosition) -> Result<Option<RecordPosition>> {
+        Ok(self.map.write().insert(key, pos))
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RecordPosition> {
+        self.map.read().get(key).copied()
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<RecordPosition> {
+        self.map.write().remove(key).ok_or(Error::KeyNotFound)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool {
+        let mut map = self.map.write();
+
+        match map.get_mut(key) {
+            Some(cur) if *cur == expected => {
+                match pos {
+                    Some(pos) => *cur = pos,
+                    None => {
+                        map.remove(key);
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn exits(&self, key: &[u8]) -> bool {
+        self.map.read().contains_key(key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.map.read().is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().len()
+    }
+
+    fn iterator(&self, range: KeyRange, reverse: bool) -> Result<Box<dyn IndexIterator + '_>> {
+        Ok(Box::new(SeekIterator::new(self, range, reverse)))
+    }
+}
+
+impl SeekIndex for BTree {
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        self.map
+            .read()
+            .range::<[u8], _>((bound, Bound::Unbounded))
+            .next()
+            .map(|(key, pos)| (key.clone(), *pos))
+    }
+
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)> {
+        self.map
+            .read()
+            .range::<[u8], _>((Bound::Unbounded, bound))
+            .next_back()
+            .map(|(key, pos)| (key.clone(), *pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(iter: &mut dyn IndexIterator) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while let Some((key, _)) = iter.next() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_btree_iterator() -> Result<()> {
+        let index = BTree::new();
+        for key in ["a", "b", "c", "d"] {
+            index.put(key.into(), RecordPosition::new(0, 0, 0))?;
+        }
+
+        let range = (Bound::Excluded("a".into()), Bound::Included("c".into()));
+        let mut iter = index.iterator(range.clone(), false)?;
+        assert_eq!(vec![b"b".to_vec(), b"c".to_vec()], keys(iter.as_mut()));
+
+        let mut iter = index.iterator(range, true)?;
+        iter.seek(b"b");
+        assert_eq!(vec![b"b".to_vec()], keys(iter.as_mut()));
+
+        // keys put after creation are visible to the cursor
+        let mut iter = index.iterator((Bound::Unbounded, Bound::Unbounded), false)?;
+        assert_eq!(Some(b"a".to_vec()), iter.next().map(|(key, _)| key));
+        index.put("aa".into(), RecordPosition::new(0, 0, 0))?;
+        index.delete(b"b")?;
+        assert_eq!(
+            vec![b"aa".to_vec(), b"c".to_vec(), b"d".to_vec()],
+            keys(iter.as_mut())
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use crate::{
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    key::Key,
+};
+
+use super::{IndexIterator, Indexer, KeyRange};
+
+#[derive(Default)]
+pub struct HashTable {
+    map: DashMap<Key, RecordPosition>,
+}
+
+impl HashTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Indexer for HashTable {
+    fn put(&self, key: Key, pos: RecordPosition) -> Result<Option<RecordPosition>> {
+        Ok(self.map.insert(key, pos))
+    }
+
+    fn get(&self, key: &[u8]) -> Option<RecordPosition> {
+        self.map.get(key).map(|pos| *pos)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<RecordPosition> {
+        self.map
+            .remove(key)
+            .map(|(_, pos)| pos)
+            .ok_or(Error::KeyNotFound)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool {
+        // the entry holds the shard lock between the check and the swap
+        match self.map.entry(key.to_vec()) {
+            Entry::Occupied(mut entry) if *entry.get() == expected => {
+                match pos {
+                    Some(pos) => {
+                        entry.insert(pos);
+                    }
+                    None => {
+                        entry.remove();
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn exits(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn iterator(&self, _range: KeyRange, _reverse: bool) -> Result<Box<dyn IndexIterator + '_>> {
+        Err(Error::Unsupported("ordered operations on a hash index"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+
+    #[test]
+    fn test_hash_table() -> Result<()> {
+        let index = HashTable::new();
+        let pos = RecordPosition::new(1, 10, 20);
+
+        assert_eq!(None, index.put("a".into(), pos)?);
+        assert_eq!(Some(pos), index.get(b"a"));
+
+        let moved = RecordPosition::new(2, 0, 20);
+        assert!(!index.compare_and_swap(b"a", moved, None));
+        assert!(index.compare_and_swap(b"a", pos, Some(moved)));
+        assert_eq!(Some(moved), index.get(b"a"));
+        assert!(!index.compare_and_swap(b"b", pos, Some(moved)));
+        assert!(!index.exits(b"b"));
+
+        assert_eq!(moved, index.delete(b"a")?);
+        assert!(index.delete(b"a").is_err());
+        assert!(index.is_empty());
+
+        assert!(index
+            .iterator((Bound::Unbounded, Bound::Unbounded), false)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -1,14 +1,26 @@
-use std::sync::Arc;
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 
-use anyhow::Result;
+use art::Art;
+use bptree::BPlusTree;
+use btree::BTree;
+use hash::HashTable;
 use skip_list::SkipList;
 
 use crate::{
     data::log_record::RecordPosition,
+    error::{Error, Result},
     key::Key,
+    options::{BitcaskOptions, IndexType},
     transaction::{Transaction, TxnSearchType},
 };
 
+pub mod art;
+pub mod bptree;
+pub mod btree;
+pub mod hash;
 pub mod skip_list;
 
 // pub interface for index like skiplist..
@@ -19,24 +31,200 @@ pub trait Indexer: Sync + Send {
 
     fn delete(&self, key: &[u8]) -> Result<RecordPosition>;
 
+    // set `key` to `pos` (remove it if `None`) only if it is still at `expected`
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool;
+
     fn exits(&self, key: &[u8]) -> bool;
 
     fn is_empty(&self) -> bool;
 
+    fn len(&self) -> usize;
+
+    // fails for indexes without key order
+    fn iterator(&self, range: KeyRange, reverse: bool) -> Result<Box<dyn IndexIterator + '_>>;
+
+    // latest version of `key_prefix` visible to `txn`, versions are keyed by prefix + ts
     fn txn_prefix_search(
         &self,
         key_prefix: &[u8],
         search_type: TxnSearchType,
         txn: &Transaction,
-    ) -> Result<(RecordPosition, u64)>;
+    ) -> Result<(RecordPosition, u64)> {
+        let mut start = key_prefix.to_vec();
+        start.extend_from_slice(&u64::MIN.to_be_bytes());
+        let mut end = key_prefix.to_vec();
+        end.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        let mut iter = self.iterator((Bound::Included(start), Bound::Included(end)), true)?;
+        while let Some((key, pos)) = iter.next() {
+            if key.len() == key_prefix.len() + 8 {
+                let ts = u64::from_be_bytes(*key.last_chunk::<8>().unwrap());
+
+                if !txn.is_visible(ts) {
+                    match search_type {
+                        TxnSearchType::Read => {
+                            continue;
+                        }
+                        TxnSearchType::Write => {
+                            return Err(Error::TxnConflict);
+                        }
+                    }
+                }
+
+                return Ok((pos, ts));
+            }
+        }
+
+        Err(Error::KeyNotFound)
+    }
+
+    // engine state saved by the last clean close, only persistent indexes keep one.
+    // without it the index is rebuilt from the data files
+    fn saved_state(&self) -> Option<IndexState> {
+        None
+    }
+
+    // write the index to disk along with `state`
+    fn persist(&self, _state: IndexState) -> Result<()> {
+        Ok(())
+    }
+}
+
+// what the engine otherwise learns by replaying the data files
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct IndexState {
+    pub batch_seq: u64,
+    pub reclaimable: u64,
+}
+
+// (start, end) bounds of an iteration
+pub type KeyRange = (Bound<Key>, Bound<Key>);
+
+// ordered cursor over one index
+pub trait IndexIterator: Send {
+    // go back to the first key (the last one if reversed)
+    fn rewind(&mut self);
+
+    // go to the first key >= `key` (<= `key` if reversed), never leaving the range
+    fn seek(&mut self, key: &[u8]);
+
+    fn next(&mut self) -> Option<(Key, RecordPosition)>;
 }
 
-pub fn new_indexer(num: u8) -> Vec<Arc<dyn Indexer>> {
-    let mut indexs: Vec<Arc<dyn Indexer>> = Vec::with_capacity(num as usize);
+pub fn new_indexer(opts: &BitcaskOptions) -> Result<Vec<Arc<dyn Indexer>>> {
+    let num = opts.index_num as usize;
+
+    // the shards of a b+tree share one file
+    if opts.index_type == IndexType::BPlusTree {
+        let trees = BPlusTree::open(&opts.db_path, num)?;
+        return Ok(trees
+            .into_iter()
+            .map(|tree| Arc::new(tree) as Arc<dyn Indexer>)
+            .collect());
+    }
+
+    let mut indexs: Vec<Arc<dyn Indexer>> = Vec::with_capacity(num);
 
     for _ in 0..num {
-        indexs.push(Arc::new(SkipList::new()));
+        let index: Arc<dyn Indexer> = match opts.index_type {
+            IndexType::SkipList => Arc::new(SkipList::new()),
+            IndexType::BTree => Arc::new(BTree::new()),
+            IndexType::HashMap => Arc::new(HashTable::new()),
+            IndexType::Art => Arc::new(Art::new()),
+            IndexType::BPlusTree => unreachable!(),
+        };
+        indexs.push(index);
     }
 
-    indexs
+    Ok(indexs)
+}
+
+// ordered lookups an index needs to get a `SeekIterator`
+pub trait SeekIndex: Sync + Send {
+    // first entry after `bound`
+    fn lower_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)>;
+
+    // last entry before `bound`
+    fn upper_bound(&self, bound: Bound<&[u8]>) -> Option<(Key, RecordPosition)>;
+}
+
+// cursor for indexes behind a lock, every step looks up the entry next to the last key
+pub struct SeekIterator<'a, T: SeekIndex> {
+    index: &'a T,
+    range: KeyRange,
+    reverse: bool,
+    // where the next lookup starts
+    cursor: Bound<Key>,
+}
+
+impl<'a, T: SeekIndex> SeekIterator<'a, T> {
+    pub fn new(index: &'a T, range: KeyRange, reverse: bool) -> Self {
+        let mut iter = Self {
+            index,
+            range,
+            reverse,
+            cursor: Bound::Unbounded,
+        };
+        iter.rewind();
+
+        iter
+    }
+}
+
+impl<T: SeekIndex> IndexIterator for SeekIterator<'_, T> {
+    fn rewind(&mut self) {
+        self.cursor = match self.reverse {
+            true => self.range.1.clone(),
+            false => self.range.0.clone(),
+        };
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        let key = key.to_vec();
+
+        // seeking in front of the range restarts from its near end
+        self.cursor = match self.reverse {
+            true if before_end(&self.range.1, &key) => Bound::Included(key),
+            false if after_start(&self.range.0, &key) => Bound::Included(key),
+            _ => return self.rewind(),
+        };
+    }
+
+    fn next(&mut self) -> Option<(Key, RecordPosition)> {
+        let bound = self.cursor.as_ref().map(|key| key.as_slice());
+        let (key, pos) = match self.reverse {
+            true => self.index.upper_bound(bound),
+            false => self.index.lower_bound(bound),
+        }?;
+
+        if !self.range.contains(&key) {
+            return None;
+        }
+        self.cursor = Bound::Excluded(key.clone());
+
+        Some((key, pos))
+    }
+}
+
+// `key` lies at or after the start bound
+pub(crate) fn after_start(start: &Bound<Key>, key: &Key) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+// `key` lies at or before the end bound
+pub(crate) fn before_end(end: &Bound<Key>, key: &Key) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
 }
@@ -1,25 +1,36 @@
-use anyhow::{Error, Ok};
-use crossbeam_skiplist::SkipMap;
+use std::ops::{Bound, RangeBounds};
 
-use crate::{data::log_record::RecordPosition, key::Key};
+use crossbeam_skiplist::{map::Entry, SkipMap};
+use parking_lot::Mutex;
 
-use super::Indexer;
+use crate::{
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    key::Key,
+};
+
+use super::{after_start, before_end, IndexIterator, Indexer, KeyRange};
 
 #[derive(Default)]
 pub struct SkipList {
     pub map: SkipMap<Key, RecordPosition>,
+    // serialize writers so that read-modify-write on a key is atomic
+    write_lock: Mutex<()>,
 }
 
 impl SkipList {
     pub fn new() -> Self {
         Self {
             map: SkipMap::new(),
+            write_lock: Mutex::new(()),
         }
     }
 }
 
 impl Indexer for SkipList {
-    fn put(&self, key: Key, pos: RecordPosition) -> anyhow::Result<Option<RecordPosition>> {
+    fn put(&self, key: Key, pos: RecordPosition) -> Result<Option<RecordPosition>> {
+        let _guard = self.write_lock.lock();
+
         if let Some(entry) = self.map.remove(&key) {
             self.map.insert(key, pos);
             Ok(Some(*entry.value()))
@@ -30,15 +41,47 @@ impl Indexer for SkipList {
     }
 
     fn get(&self, key: &[u8]) -> Option<RecordPosition> {
-        self.map.get(key).map(|entry| *entry.value())
+        let get = || self.map.get(key).map(|entry| *entry.value());
+
+        // a key being replaced is briefly missing, look again once no write is in progress
+        get().or_else(|| {
+            let _guard = self.write_lock.lock();
+            get()
+        })
     }
 
-    fn delete(&self, key: &[u8]) -> anyhow::Result<RecordPosition> {
+    fn delete(&self, key: &[u8]) -> Result<RecordPosition> {
+        let _guard = self.write_lock.lock();
+
         if let Some(entry) = self.map.remove(key) {
             return Ok(*entry.value());
         }
 
-        Err(Error::msg("key not found!"))
+        Err(Error::KeyNotFound)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: RecordPosition,
+        pos: Option<RecordPosition>,
+    ) -> bool {
+        let _guard = self.write_lock.lock();
+
+        match self.map.get(key) {
+            Some(entry) if *entry.value() == expected => {
+                match pos {
+                    Some(pos) => {
+                        self.map.insert(key.to_vec(), pos);
+                    }
+                    None => {
+                        entry.remove();
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
     }
 
     fn exits(&self, key: &[u8]) -> bool {
@@ -49,34 +92,63 @@ impl Indexer for SkipList {
         self.map.is_empty()
     }
 
-    fn txn_prefix_search(
-        &self,
-        key_prefix: &[u8],
-        search_type: crate::transaction::TxnSearchType,
-        txn: &crate::transaction::Transaction,
-    ) -> anyhow::Result<(RecordPosition, u64)> {
-        // todo! use bound to narrow search range
-        for entry in self.map.iter().rev() {
-            let key = entry.key();
-
-            if key.len() - key_prefix.len() == 8 && key.starts_with(key_prefix) {
-                let ts = u64::from_be_bytes(*key.last_chunk::<8>().unwrap());
-
-                if !txn.is_visible(ts) {
-                    match search_type {
-                        crate::transaction::TxnSearchType::Read => {
-                            continue;
-                        }
-                        crate::transaction::TxnSearchType::Write => {
-                            return Err(anyhow::Error::msg("txn conflict!"));
-                        }
-                    }
-                }
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn iterator(&self, range: KeyRange, reverse: bool) -> Result<Box<dyn IndexIterator + '_>> {
+        let mut iter = SkipListIterator {
+            map: &self.map,
+            range,
+            reverse,
+            current: None,
+        };
+        iter.rewind();
+
+        Ok(Box::new(iter))
+    }
+}
+
+pub struct SkipListIterator<'a> {
+    map: &'a SkipMap<Key, RecordPosition>,
+    range: KeyRange,
+    reverse: bool,
+    // entry to be returned by the next call of `next`
+    current: Option<Entry<'a, Key, RecordPosition>>,
+}
+
+impl IndexIterator for SkipListIterator<'_> {
+    fn rewind(&mut self) {
+        self.current = match self.reverse {
+            true => self.map.upper_bound(self.range.1.as_ref()),
+            false => self.map.lower_bound(self.range.0.as_ref()),
+        };
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        let key = key.to_vec();
 
-                return Ok((*entry.value(), ts));
+        // seeking in front of the range restarts from its near end
+        self.current = match self.reverse {
+            true if before_end(&self.range.1, &key) => self.map.upper_bound(Bound::Included(&key)),
+            false if after_start(&self.range.0, &key) => {
+                self.map.lower_bound(Bound::Included(&key))
             }
+            _ => return self.rewind(),
+        };
+    }
+
+    fn next(&mut self) -> Option<(Key, RecordPosition)> {
+        let entry = self.current.take()?;
+        if !self.range.contains(entry.key()) {
+            return None;
         }
 
-        Err(anyhow::Error::msg("txn search: key not found!"))
+        self.current = match self.reverse {
+            true => entry.prev(),
+            false => entry.next(),
+        };
+
+        Some((entry.key().clone(), *entry.value()))
     }
 }
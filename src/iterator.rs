@@ -0,0 +1,318 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    data::log_record::RecordPosition,
+    error::{Error, Result},
+    index::{IndexIterator, KeyRange},
+    key::Key,
+    options::IteratorOptions,
+    snapshot::SnapshotState,
+    storage::Bitcask,
+    utils::now_millis,
+};
+
+// ordered iterator over all index shards, expired keys are left in until a merge drops them
+pub struct Iter<'a> {
+    pub(crate) storage: &'a Bitcask,
+    index_iters: Vec<Box<dyn IndexIterator + 'a>>,
+    // next key of every shard, keys never repeat between shards
+    heads: Vec<Option<(Key, RecordPosition)>>,
+    reverse: bool,
+}
+
+// key with a lazily loaded value
+pub struct Entry<'a> {
+    pub(crate) storage: &'a Bitcask,
+    pub(crate) key: Key,
+    pub(crate) pos: RecordPosition,
+    // the value is read as of this snapshot
+    pub(crate) snapshot: Option<&'a SnapshotState>,
+}
+
+impl Bitcask {
+    pub fn iter(&self, opts: IteratorOptions) -> Result<Iter<'_>> {
+        let range = prefix_range(&opts.prefix);
+
+        self.iter_range(range, opts.reverse)
+    }
+
+    // keys starting with `prefix`, in ascending order
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<Iter<'_>> {
+        self.iter(IteratorOptions {
+            prefix: prefix.as_ref().to_vec(),
+            ..Default::default()
+        })
+    }
+
+    // keys inside `range`, in ascending order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<Iter<'_>> {
+        let to_key = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+
+        self.iter_range(
+            (to_key(range.start_bound()), to_key(range.end_bound())),
+            false,
+        )
+    }
+
+    fn iter_range(&self, range: KeyRange, reverse: bool) -> Result<Iter<'_>> {
+        let index_iters = self
+            .indexs
+            .iter()
+            .map(|index| index.iterator(range.clone(), reverse))
+            .collect::<Result<_>>()?;
+
+        let mut iter = Iter {
+            storage: self,
+            index_iters,
+            heads: Vec::new(),
+            reverse,
+        };
+        iter.fill_heads();
+
+        Ok(iter)
+    }
+}
+
+// range of all keys starting with `prefix`
+pub(crate) fn prefix_range(prefix: &[u8]) -> KeyRange {
+    if prefix.is_empty() {
+        return (Bound::Unbounded, Bound::Unbounded);
+    }
+
+    // the smallest key greater than every key with this prefix
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+impl<'a> Iter<'a> {
+    pub fn rewind(&mut self) {
+        self.index_iters.iter_mut().for_each(|iter| iter.rewind());
+        self.fill_heads();
+    }
+
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) {
+        let key = key.as_ref();
+
+        self.index_iters.iter_mut().for_each(|iter| iter.seek(key));
+        self.fill_heads();
+    }
+
+    // the key `next` returns without moving past it
+    pub(crate) fn peek(&self) -> Option<&(Key, RecordPosition)> {
+        self.heads[self.chosen()?].as_ref()
+    }
+
+    // shard holding the next key
+    fn chosen(&self) -> Option<usize> {
+        let mut chosen: Option<usize> = None;
+
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((key, _)) = head else {
+                continue;
+            };
+
+            let better = match chosen.and_then(|c| self.heads[c].as_ref()) {
+                None => true,
+                Some((best, _)) if self.reverse => key > best,
+                Some((best, _)) => key < best,
+            };
+
+            if better {
+                chosen = Some(i);
+            }
+        }
+
+        chosen
+    }
+
+    fn fill_heads(&mut self) {
+        self.heads = self
+            .index_iters
+            .iter_mut()
+            .map(|iter| iter.next())
+            .collect();
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chosen = self.chosen()?;
+        let (key, pos) =
+            std::mem::replace(&mut self.heads[chosen], self.index_iters[chosen].next())?;
+
+        Some(Entry {
+            storage: self.storage,
+            key,
+            pos,
+            snapshot: None,
+        })
+    }
+}
+
+impl Entry<'_> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> Result<Vec<u8>> {
+        if let Some(snapshot) = self.snapshot {
+            return self.storage.get_from_snapshot(snapshot, &self.key);
+        }
+
+        let record = self.storage.get_record_with_key(&self.key, self.pos)?;
+
+        if record.is_expired(now_millis()) {
+            return Err(Error::KeyNotFound);
+        }
+
+        Ok(record.value().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Result, iterator::Iter, options::IteratorOptions, storage::Bitcask, utils::TestDir,
+    };
+
+    #[test]
+    fn test_iter_forward_and_reverse() -> Result<()> {
+        let dir = TestDir::new("bitcask_iter_order");
+        let bitcask = Bitcask::open(dir.options())?;
+
+        for i in (0..300).rev() {
+            bitcask.put(format!("{:05}", i), format!("value-{}", i))?;
+        }
+        bitcask.delete(format!("{:05}", 7))?;
+
+        let keys: Vec<Vec<u8>> = bitcask
+            .iter(IteratorOptions::default())?
+            .map(|entry| entry.key().to_vec())
+            .collect();
+        let expected: Vec<Vec<u8>> = (0..300)
+            .filter(|i| *i != 7)
+            .map(|i| format!("{:05}", i).into_bytes())
+            .collect();
+        assert_eq!(expected, keys);
+
+        let keys: Vec<Vec<u8>> = bitcask
+            .iter(IteratorOptions {
+                reverse: true,
+                ..Default::default()
+            })?
+            .map(|entry| entry.key().to_vec())
+            .collect();
+        assert_eq!(expected.into_iter().rev().collect::<Vec<_>>(), keys);
+
+        let entry = bitcask.iter(IteratorOptions::default())?.next().unwrap();
+        assert_eq!(b"value-0".to_vec(), entry.value()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_seek() -> Result<()> {
+        let dir = TestDir::new("bitcask_iter_seek");
+        let bitcask = Bitcask::open(dir.options())?;
+
+        for key in ["a", "c", "e", "g"] {
+            bitcask.put(key, key)?;
+        }
+
+        let mut iter = bitcask.iter(IteratorOptions::default())?;
+        iter.seek("b");
+        assert_eq!(b"c", iter.next().unwrap().key());
+        assert_eq!(b"e", iter.next().unwrap().key());
+
+        iter.rewind();
+        assert_eq!(b"a", iter.next().unwrap().key());
+
+        let mut iter = bitcask.iter(IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        })?;
+        iter.seek("f");
+        assert_eq!(b"e", iter.next().unwrap().key());
+        assert_eq!(b"c", iter.next().unwrap().key());
+
+        iter.seek("z");
+        assert_eq!(b"g", iter.next().unwrap().key());
+
+        Ok(())
+    }
+
+    fn keys(iter: Iter) -> Vec<String> {
+        iter.map(|entry| String::from_utf8(entry.key().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        let dir = TestDir::new("bitcask_iter_prefix");
+        let bitcask = Bitcask::open(dir.options())?;
+
+        for key in ["user:1", "user:2", "user:3", "usera", "post:1", "user"] {
+            bitcask.put(key, key)?;
+        }
+        bitcask.put([0xff, 0xff, 0x01], "max")?;
+        bitcask.put([0xff, 0xff], "max")?;
+        bitcask.delete("user:2")?;
+
+        assert_eq!(
+            vec!["user:1", "user:3"],
+            keys(bitcask.scan_prefix("user:")?)
+        );
+        assert_eq!(
+            vec!["user", "user:1", "user:3", "usera"],
+            keys(bitcask.scan_prefix("user")?)
+        );
+        assert_eq!(2, bitcask.scan_prefix([0xff, 0xff])?.count());
+        assert_eq!(0, bitcask.scan_prefix("none")?.count());
+
+        let reversed = bitcask.iter(IteratorOptions {
+            prefix: "user:".into(),
+            reverse: true,
+        })?;
+        assert_eq!(vec!["user:3", "user:1"], keys(reversed));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> Result<()> {
+        let dir = TestDir::new("bitcask_iter_range");
+        let bitcask = Bitcask::open(dir.options())?;
+
+        for i in 0..10 {
+            bitcask.put(format!("k{}", i), "v")?;
+        }
+
+        assert_eq!(vec!["k2", "k3", "k4"], keys(bitcask.range("k2".."k5")?));
+        assert_eq!(
+            vec!["k2", "k3", "k4", "k5"],
+            keys(bitcask.range("k2"..="k5")?)
+        );
+        assert_eq!(vec!["k8", "k9"], keys(bitcask.range("k8"..)?));
+        assert_eq!(vec!["k0", "k1"], keys(bitcask.range(.."k2")?));
+        assert_eq!(10, bitcask.range::<&str>(..)?.count());
+
+        let mut iter = bitcask.range("k2".."k5")?;
+        iter.seek("k0");
+        assert_eq!(vec!["k2", "k3", "k4"], keys(iter));
+
+        let mut iter = bitcask.range("k2".."k5")?;
+        iter.seek("k4");
+        assert_eq!(vec!["k4"], keys(iter));
+
+        Ok(())
+    }
+}
@@ -1,12 +1,38 @@
-use anyhow::{Ok, Result};
+use crate::{
+    error::{Error, Result},
+    options::IndexType,
+};
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
 
+// keys have to fit several times into a page of the b+tree index
+pub const MAX_KEY_SIZE: usize = 1024;
+// a transaction appends its ts to the keys it writes
+pub(crate) const TXN_TS_SIZE: usize = 8;
+
 #[inline(always)]
 pub fn check_key_valid(key: &Key) -> Result<()> {
     if key.is_empty() {
-        return Err(anyhow::Error::msg("key is not valid!"));
+        return Err(Error::KeyEmpty);
+    }
+
+    Ok(())
+}
+
+// only the b+tree index limits the key size. checked before a write, a record the
+// index rejects would break the next open
+pub fn check_key_size(key: &Key, index_type: IndexType) -> Result<()> {
+    if index_type != IndexType::BPlusTree {
+        return Ok(());
+    }
+
+    let max = MAX_KEY_SIZE - TXN_TS_SIZE;
+    if key.len() > max {
+        return Err(Error::KeyTooLarge {
+            size: key.len(),
+            max,
+        });
     }
 
     Ok(())
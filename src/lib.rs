@@ -1,18 +1,37 @@
+pub mod backup;
 pub mod batch_write;
+pub mod conditional_write;
 pub(crate) mod data;
+pub mod dump;
+pub mod error;
 pub(crate) mod file;
+pub(crate) mod flusher;
+pub(crate) mod group_commit;
 pub(crate) mod index;
+pub mod iterator;
 pub(crate) mod key;
 pub mod merge;
 pub mod options;
+pub mod repair;
+pub mod secondary;
+pub mod snapshot;
 pub mod storage;
+pub mod subscribe;
 pub mod transaction;
 pub(crate) mod utils;
+pub mod verify;
+
+pub use backup::restore;
+pub use error::{Error, Result};
+pub use verify::verify;
 
 pub mod consts {
     pub const DATA_FILE_SUFFIX: &str = ".data";
+    pub const DATA_HINT_FILE_SUFFIX: &str = ".hint";
     pub const HINT_FILE_NAME: &str = "index.HINT";
     pub const MERGE_FILE_NAME: &str = "db.MERGE";
     pub const FILE_LOCK: &str = "FILE_LOCK";
     pub const TXN_FILE: &str = ".TXN";
+    pub const BPTREE_INDEX_FILE_NAME: &str = "index.BPTREE";
+    pub const BACKUP_MANIFEST_FILE: &str = "MANIFEST";
 }
@@ -0,0 +1,166 @@
+use std::{io, path::Path, process::ExitCode};
+
+use bitcask::{
+    dump::{dump, BatchState, DumpEntry, DumpRecord, RecordType},
+    options::BitcaskOptions,
+    storage::Bitcask,
+    verify, Error, Result,
+};
+
+const USAGE: &str = "usage: bitcask <db path> <command> [args]
+
+commands:
+    get <key>                 print the value of a key
+    put <key> <value>         set a key
+    delete <key>              delete a key
+    scan [--prefix <prefix>]  print keys and values in order
+    stat                      print key, file and disk usage counts
+    merge                     rewrite the data files without garbage
+    dump <file> [--hex]       print the records of a data, hint or merge file
+    verify                    check every file of a closed database";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let [path, command, args @ ..] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    match run(Path::new(path), command, args) {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// `None` if the arguments do not match any command
+fn run(path: &Path, command: &str, args: &[&str]) -> Result<Option<ExitCode>> {
+    // commands working on single files never open the database
+    match (command, args) {
+        ("dump", [file]) | ("dump", [file, "--hex"]) => {
+            let hex = args.len() == 2;
+            for entry in dump(path.join(file))? {
+                match entry {
+                    DumpEntry::Record(record) => println!("{}", format_record(&record, hex)),
+                    DumpEntry::Garbage { offset, len } => {
+                        println!("offset={} size={} garbage", offset, len)
+                    }
+                }
+            }
+            return Ok(Some(ExitCode::SUCCESS));
+        }
+        ("verify", []) => {
+            let report = verify(path)?;
+            println!("{}", report);
+            return Ok(Some(if report.is_ok() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }));
+        }
+        ("dump" | "verify", _) => return Ok(None),
+        _ => {}
+    }
+
+    // open would create a database under a mistyped path
+    if !path.is_dir() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no database at {}", path.display()),
+        )));
+    }
+
+    // commands that only read share the database with each other
+    let bitcask = Bitcask::open(BitcaskOptions {
+        db_path: path.to_path_buf(),
+        read_only: matches!(command, "get" | "scan" | "stat"),
+        ..Default::default()
+    })?;
+
+    match (command, args) {
+        ("get", [key]) => println!("{}", display(&bitcask.get(key)?, false)),
+        ("put", [key, value]) => bitcask.put(key, value)?,
+        ("delete", [key]) => bitcask.delete(key)?,
+        ("scan", []) | ("scan", ["--prefix", _]) => {
+            for entry in bitcask.scan_prefix(args.get(1).unwrap_or(&""))? {
+                // a key may expire between the index lookup and the read
+                match entry.value() {
+                    Ok(value) => println!(
+                        "{}\t{}",
+                        display(entry.key(), false),
+                        display(&value, false)
+                    ),
+                    Err(Error::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        ("stat", []) => {
+            let stat = bitcask.stat()?;
+            println!("keys: {}", stat.key_num);
+            println!("data files: {}", stat.data_file_num);
+            println!("reclaimable bytes: {}", stat.reclaimable_size);
+            println!("disk used bytes: {}", stat.disk_used);
+        }
+        ("merge", []) => bitcask.merge()?,
+        _ => return Ok(None),
+    }
+
+    bitcask.close()?;
+
+    Ok(Some(ExitCode::SUCCESS))
+}
+
+fn format_record(record: &DumpRecord, hex: bool) -> String {
+    let record_type = match record.record_type {
+        RecordType::Normal => "normal",
+        RecordType::Deleted => "deleted",
+    };
+    let batch = match record.batch_state {
+        BatchState::Enable(seq) => format!("enable({})", seq),
+        BatchState::Finish(seq) => format!("finish({})", seq),
+        BatchState::Disable => "disable".to_string(),
+        BatchState::Txn(ts) => format!("txn({})", ts),
+        BatchState::TxnCommit(ts) => format!("commit({})", ts),
+        BatchState::Snapshot => "snapshot".to_string(),
+    };
+    let value = match record.position {
+        Some(pos) => format!(
+            "file {} offset {} size {}",
+            pos.file_id, pos.offset, pos.size
+        ),
+        None => display(&record.value, hex),
+    };
+
+    let mut line = format!(
+        "offset={} size={} type={} batch={} key={} value={} crc={}",
+        record.offset,
+        record.size,
+        record_type,
+        batch,
+        display(&record.key, hex),
+        value,
+        if record.crc_ok { "ok" } else { "bad" }
+    );
+    if let Some(expire_at) = record.expire_at {
+        line += &format!(" expire_at={}", expire_at);
+    }
+
+    line
+}
+
+// utf-8 as is unless `hex` is set, anything else as hex
+fn display(bytes: &[u8], hex: bool) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !hex && !s.chars().any(char::is_control) => s.to_string(),
+        _ => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
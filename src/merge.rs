@@ -1,20 +1,25 @@
 use std::{
     collections::HashMap,
-    fs,
+    fs, io,
     path::{Path, PathBuf},
+    sync::{atomic::Ordering, Weak},
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use anyhow::Result;
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
 use parking_lot::RwLock;
 
 use crate::{
     consts::{DATA_FILE_SUFFIX, HINT_FILE_NAME, MERGE_FILE_NAME},
     data::{
         datafile::DataFile,
-        log_record::{BatchState, Record, RecordPosition},
+        log_record::{BatchState, Record, RecordPosition, RecordType},
     },
+    error::{Error, Result},
+    options::IoType,
     storage::Bitcask,
-    utils::{get_data_file_path, get_merge_path},
+    utils::{get_data_file_path, get_data_hint_file_path, get_merge_path, now_millis},
 };
 
 pub struct MergeEngine {
@@ -58,11 +63,11 @@ impl MergeEngine {
 
         assert_eq!(prev_offset + write_size as u64, active_file.write_offset);
 
-        return Ok(RecordPosition {
+        Ok(RecordPosition {
             file_id: active_file.id,
             offset: prev_offset,
             size: write_size,
-        });
+        })
     }
 
     pub fn sync(&self) -> Result<()> {
@@ -70,99 +75,290 @@ impl MergeEngine {
     }
 }
 
+// background thread merging the storage once enough space is reclaimable
+pub(crate) struct MergeWorker {
+    shutdown: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl MergeWorker {
+    pub(crate) fn spawn(storage: Weak<Bitcask>, interval: Duration) -> Self {
+        let (shutdown, rx) = bounded(1);
+
+        // wake up every interval until shutdown
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                // the storage has been dropped
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+
+                match storage.need_merge() {
+                    Ok(true) => {
+                        if let Err(e) = storage.merge() {
+                            log::error!("auto merge error: {}", e);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::error!("auto merge stat error: {}", e),
+                }
+            }
+        });
+
+        Self { shutdown, handle }
+    }
+
+    pub(crate) fn stop(self) {
+        let _ = self.shutdown.send(());
+
+        if self.handle.join().is_err() {
+            log::error!("auto merge thread panicked!");
+        }
+    }
+}
+
 impl Bitcask {
+    fn need_merge(&self) -> Result<bool> {
+        let stat = self.stat()?;
+        if stat.disk_used == 0 {
+            return Ok(false);
+        }
+
+        let ratio = stat.reclaimable_size as f32 / stat.disk_used as f32;
+
+        Ok(ratio >= self.opts.merge_ratio)
+    }
+
     pub fn merge(&self) -> Result<()> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         if self.is_empty() {
             return Ok(());
         }
 
-        let _ = self
-            .merge_lock
-            .try_lock()
-            .ok_or(anyhow::Error::msg("bitcask engine is merging!"))?;
+        let _guard = self.merge_lock.try_lock().ok_or(Error::MergeInProgress)?;
 
         let merge_path = get_merge_path(&self.opts.db_path);
         if merge_path.is_dir() {
             std::fs::remove_dir_all(&merge_path).unwrap();
         }
 
-        std::fs::create_dir_all(&merge_path)
-            .map_err(|_| anyhow::Error::msg("create merge dir error!"))?;
+        std::fs::create_dir_all(&merge_path)?;
 
-        let merge_files = self.get_merge_files()?;
+        let (merge_files, next_file_id) = self.get_merge_files()?;
         let merge_engine = MergeEngine::new(&merge_path, self.opts.max_file_size)?;
         let mut hint_file = DataFile::hint_file(&merge_path)?;
 
-        let mut max_file_id = 0;
+        // position of the record behind each hint, the index only moves if it still points there
+        let mut scanned = Vec::new();
+        // size of the records read, bad ones skipped are counted nowhere
+        let mut input_size = 0;
+
+        let now = now_millis();
         for file in merge_files.iter() {
             let mut offset = 0;
             loop {
-                let (size, mut record) = match file.read_record(offset) {
-                    Ok(reader) => (reader.size(), reader.to_record()),
-                    Err(_) => break,
+                let reader = match file.read_record(offset) {
+                    Ok(reader) => reader,
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(Error::Corrupted { .. }) => {
+                        match self.skip_bad_record(file, offset, false)? {
+                            Some(next) => {
+                                offset = next;
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                    Err(e) => return Err(e),
                 };
+                let (size, mut record) = (reader.size(), reader.to_record());
+                input_size += size;
 
-                if let BatchState::Finish(_) = record.batch_state {
+                if let BatchState::Finish(_) | BatchState::TxnCommit(_) | BatchState::Snapshot =
+                    record.batch_state
+                {
                     offset += size as u64;
                     continue;
                 }
 
                 if let Some(pos) = self.get_index(&record.key).get(&record.key) {
                     if pos.file_id == file.id && pos.offset == offset {
-                        record.disable_batch()?;
-                        let merge_pos = merge_engine.append_record(&record)?;
-                        hint_file.write_record(&Record::normal(record.key, merge_pos.encode()))?;
+                        scanned.push(pos);
+
+                        if record.is_expired(now) {
+                            // drop the value, the tombstone hint removes it from the index
+                            hint_file.write_record(&Record::deleted(record.key))?;
+                        } else {
+                            record.disable_batch();
+                            let merge_pos = merge_engine.append_record(&record)?;
+
+                            let mut hint = Record::normal(record.key, merge_pos.encode());
+                            hint.expire_at = record.expire_at;
+                            hint_file.write_record(&hint)?;
+                        }
                     }
                 }
 
                 offset += size as u64;
-                max_file_id = max_file_id.max(file.id);
             }
         }
 
         hint_file.sync()?;
+
+        // the records live snapshots still see are copied too, the ones they come to see
+        // before the swap are copied by `apply_merge_files`
+        let mut moved = HashMap::new();
+        let snapshots = self.snapshots.read().clone();
+        self.copy_snapshot_records(&snapshots, &merge_files, &mut moved, |record| {
+            merge_engine.append_record(record)
+        })?;
+        drop(snapshots);
         merge_engine.sync()?;
 
-        let mut merge_file = DataFile::merge_file(&merge_path)?;
-        let next_file_id = max_file_id + 1;
+        let merged_file_num = merge_engine.active_file.read().id + 1;
+        drop(merge_engine);
 
-        let merge_record = Record::merge_finished(next_file_id);
+        let mut merge_file = DataFile::merge_file(&merge_path)?;
+        let merge_record = Record::merge_finished(next_file_id, merged_file_num);
         merge_file.write_record(&merge_record)?;
         merge_file.sync()?;
 
-        Ok(())
+        self.apply_merge_files(&merge_files, input_size, &scanned, moved)
     }
 
-    fn get_merge_files(&self) -> Result<Vec<DataFile>> {
-        let mut old_files = self.old_files.write();
+    // seal the active file, all sealed files are going to be merged.
+    // return them with the id of the new active file
+    fn get_merge_files(&self) -> Result<(Vec<DataFile>, u32)> {
+        // keep a batch from spanning the merged and unmerged files
+        let _batch_guard = self.batch_lock.lock();
+        // wait for writes in flight, every record of the sealed files is indexed before the scan
+        let _shard_guards: Vec<_> = self.shard_locks.iter().map(|lock| lock.lock()).collect();
 
         let mut active_file = self.active_file.write();
-        active_file.sync()?;
-        let prev_file_id = active_file.id;
-
-        let prev_active_file = std::mem::replace(
-            &mut *active_file,
-            DataFile::new(&self.opts.db_path, prev_file_id + 1)?,
-        );
+        let mut old_files = self.old_files.write();
 
-        old_files.insert(prev_file_id, prev_active_file);
+        self.rotate_active_file(&mut active_file, &mut old_files)?;
+        let next_file_id = active_file.id;
 
+        // merge only scans sealed files
         let res = old_files
             .keys()
-            .map(|key| DataFile::new(&self.opts.db_path, *key).unwrap())
+            .map(|key| {
+                DataFile::new_with_io_type(&self.opts.db_path, *key, IoType::MemoryMap).unwrap()
+            })
             .collect();
 
-        Ok(res)
+        Ok((res, next_file_id))
     }
 
-    pub fn load_merge_file(path: impl AsRef<Path>) -> Result<()> {
+    // replace the merged files with the merge output while serving reads. the files are
+    // moved, opened and the hints read first, only the swap in memory blocks other threads
+    fn apply_merge_files(
+        &self,
+        merge_files: &[DataFile],
+        input_size: usize,
+        scanned: &[RecordPosition],
+        mut moved: HashMap<(u32, u64), RecordPosition>,
+    ) -> Result<()> {
+        let merge_mark =
+            Self::load_merge_file(&self.opts.db_path)?.ok_or(Error::MergeFileBroken)?;
+
+        // on an error the old files go on serving reads, their handles stay readable
+        // after the merged files replaced them on disk
+        let mut merged_files = (0..merge_mark.merged_file_num.unwrap_or(0))
+            .map(|id| DataFile::new_with_io_type(&self.opts.db_path, id, self.opts.io_type))
+            .collect::<Result<Vec<_>>>()?;
+
+        let hint_file = DataFile::hint_file(&self.opts.db_path)?;
+        let mut hints = Vec::with_capacity(scanned.len());
+        let mut offset = 0;
+        for scanned_pos in scanned.iter().copied() {
+            let record = hint_file.read_record(offset)?;
+            offset += record.size() as u64;
+
+            // expired when merging
+            let merge_pos = match record.record_type {
+                RecordType::Deleted => None,
+                RecordType::Normal => Some(RecordPosition::decode(record.value())),
+            };
+            hints.push((record.key().to_vec(), scanned_pos, merge_pos));
+        }
+
+        // no index change until the preimages point at the merged files
+        let snapshots = self.snapshots.write();
+
+        // records snapshots came to see since the copy go after the last merged file
+        let mut tail = None;
+        self.copy_snapshot_records(&snapshots, merge_files, &mut moved, |record| {
+            let file = match &mut tail {
+                Some(file) => file,
+                None => {
+                    let id = merged_files.last().map_or(0, |file| file.id);
+                    let mut file = DataFile::new(&self.opts.db_path, id)?;
+                    file.write_offset = file.size()?;
+                    tail.insert(file)
+                }
+            };
+
+            let offset = file.write_offset;
+            let size = file.write_record(record)?;
+            Ok(RecordPosition::new(file.id, offset, size))
+        })?;
+        if let Some(file) = tail {
+            file.sync()?;
+            if let Some(last) = merged_files.last_mut() {
+                last.set_io_type(&self.opts.db_path, self.opts.io_type)?;
+            }
+        }
+
+        let mut old_files = self.old_files.write();
+        for file in merge_files {
+            old_files.remove(&file.id);
+        }
+        for file in merged_files {
+            old_files.insert(file.id, file);
+        }
+
+        // the input records still indexed, live or expired, are the only ones not
+        // counted as reclaimable
+        let mut indexed_size = 0;
+        let mut dead_size = 0;
+        for (key, scanned_pos, merge_pos) in hints {
+            let index = self.get_index(&key);
+
+            // a key written since the scan keeps its newer position
+            if index.compare_and_swap(&key, scanned_pos, merge_pos) {
+                indexed_size += scanned_pos.size as usize;
+            } else if let Some(merge_pos) = merge_pos {
+                dead_size += merge_pos.size as usize;
+            }
+        }
+        drop(old_files);
+
+        self.move_snapshot_records(&snapshots, moved);
+
+        let freed = input_size - indexed_size;
+        let _ = self
+            .reclaimable
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reclaimable| {
+                Some(reclaimable - freed + dead_size)
+            });
+
+        Ok(())
+    }
+
+    // move the files of a finished merge into the db path, return the merge mark if any.
+    // it is idempotent so an interrupted move can be redone on the next open
+    pub fn load_merge_file(path: impl AsRef<Path>) -> Result<Option<MergeMark>> {
         let merge_path = get_merge_path(&path);
         if !merge_path.is_dir() {
-            return Ok(());
+            return Ok(None);
         }
 
-        let files = std::fs::read_dir(&merge_path)
-            .map_err(|_| anyhow::Error::msg("read merge dir error!"))?;
+        let files = std::fs::read_dir(&merge_path)?;
 
         let merge_files: Vec<String> = files
             .filter_map(|f| f.ok())
@@ -182,31 +378,70 @@ impl Bitcask {
         });
 
         if !merge_finished_flag {
-            fs::remove_dir_all(&merge_path).unwrap();
-            return Ok(());
+            fs::remove_dir_all(&merge_path)?;
+            return Ok(None);
         }
 
-        let merge_file = DataFile::merge_file(&merge_path)?;
-        let merge_record = merge_file.read_record(0)?;
-        let bytes = merge_record.value().first_chunk::<4>().unwrap();
-        let next_file_id = u32::from_be_bytes(*bytes);
+        let merge_mark = MergeMark::read(&merge_path)?;
 
-        for id in 0..next_file_id {
+        // merged files take the place of the lowest ids, the rest are deleted
+        let first_removed = merge_mark.merged_file_num.unwrap_or(0);
+        for id in first_removed..merge_mark.next_file_id {
             let filename = get_data_file_path(&path, id);
             if filename.is_file() {
-                std::fs::remove_file(filename).unwrap();
+                std::fs::remove_file(filename)?;
             }
         }
 
-        for filename in merge_files {
+        // merged files are covered by the merge hint file
+        for id in 0..merge_mark.next_file_id {
+            let filename = get_data_hint_file_path(&path, id);
+            if filename.is_file() {
+                std::fs::remove_file(filename)?;
+            }
+        }
+
+        // the merge finished file goes last, it marks the whole move as done
+        let (marks, merge_files): (Vec<String>, Vec<String>) = merge_files
+            .into_iter()
+            .partition(|filename| filename == MERGE_FILE_NAME);
+
+        for filename in merge_files.into_iter().chain(marks) {
             let src = merge_path.join(&filename);
             let dst = path.as_ref().join(&filename);
 
-            std::fs::rename(src, dst).unwrap();
+            std::fs::rename(src, dst)?;
         }
 
-        std::fs::remove_dir(merge_path).unwrap();
+        std::fs::remove_dir_all(merge_path)?;
 
-        Ok(())
+        Ok(Some(merge_mark))
+    }
+}
+
+// content of the merge finished file
+pub struct MergeMark {
+    // files before it have been merged
+    pub next_file_id: u32,
+    // merged files are numbered from 0, absent in files of older versions
+    pub merged_file_num: Option<u32>,
+}
+
+impl MergeMark {
+    pub(crate) fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let merge_file = DataFile::from_path(path.as_ref().join(MERGE_FILE_NAME))?;
+        let record = merge_file.read_record(0)?;
+        let value = record.value();
+
+        let next_file_id =
+            u32::from_be_bytes(*value.first_chunk::<4>().ok_or(Error::MergeFileBroken)?);
+        let merged_file_num = value
+            .get(4..8)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+
+        Ok(Self {
+            next_file_id,
+            merged_file_num,
+        })
     }
 }
@@ -1,26 +1,69 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::Result;
+use crate::error::{Error, Result};
 
 #[derive(Clone)]
 pub struct BitcaskOptions {
     pub db_path: PathBuf,
     pub max_file_size: usize,
-    pub write_sync: bool,
+    // when appended records are synced to disk
+    pub sync_policy: SyncPolicy,
     pub index_num: u8,
+    pub index_type: IndexType,
+    // how sealed data files are read, the active file always uses `StandardFIO`
+    pub io_type: IoType,
+    // what to do with bad records found while loading the data files
+    pub recovery_mode: RecoveryMode,
+    // share the database with other read only opens, writes fail with `Error::ReadOnly`.
+    // files are only read, memory mapped
+    pub read_only: bool,
+
+    // merge in background once reclaimable / disk_used reaches `merge_ratio`
+    pub auto_merge: bool,
+    pub merge_ratio: f32,
+    // how often the background merge checks the ratio
+    pub merge_interval: Duration,
 }
 
 pub fn check_options(opts: &BitcaskOptions) -> Result<()> {
     let path = opts.db_path.to_str();
     if path.is_none() || path.unwrap().is_empty() {
-        return Err(anyhow::Error::msg(
-            "db path should not be empty in options!",
-        ));
+        return Err(Error::InvalidOptions("db path should not be empty"));
     }
 
     if opts.max_file_size == 0 {
-        return Err(anyhow::Error::msg(
-            "max file size should not be 0 in options!",
+        return Err(Error::InvalidOptions("max file size should not be 0"));
+    }
+
+    match opts.sync_policy {
+        SyncPolicy::EveryBytes(0) => {
+            return Err(Error::InvalidOptions("sync bytes should not be 0"))
+        }
+        SyncPolicy::Interval(interval) if interval.is_zero() => {
+            return Err(Error::InvalidOptions("sync interval should not be 0"))
+        }
+        _ => {}
+    }
+
+    if opts.merge_ratio <= 0.0 || opts.merge_ratio > 1.0 {
+        return Err(Error::InvalidOptions("merge ratio should be in (0, 1]"));
+    }
+
+    if opts.read_only && opts.auto_merge {
+        return Err(Error::InvalidOptions(
+            "auto merge is not supported in read only mode",
+        ));
+    }
+
+    if opts.read_only && opts.index_type == IndexType::BPlusTree {
+        return Err(Error::InvalidOptions(
+            "b+tree index is not supported in read only mode",
+        ));
+    }
+
+    if opts.auto_merge && opts.merge_interval.is_zero() {
+        return Err(Error::InvalidOptions(
+            "merge interval should not be 0 when auto merge is enabled",
         ));
     }
 
@@ -32,14 +75,22 @@ impl Default for BitcaskOptions {
         Self {
             db_path: "/tmp/bitcask_tmp".into(),
             max_file_size: 256 << 10,
-            write_sync: false,
+            sync_policy: SyncPolicy::Never,
             index_num: 8,
+            index_type: IndexType::SkipList,
+            io_type: IoType::StandardFIO,
+            recovery_mode: RecoveryMode::TruncateTail,
+            read_only: false,
+            auto_merge: false,
+            merge_ratio: 0.5,
+            merge_interval: Duration::from_secs(60),
         }
     }
 }
 
 pub struct WriteBatchOptions {
     pub max_batch_size: usize,
+    // sync the batch on commit even if the sync policy would not, it follows the policy otherwise
     pub write_sync: bool,
 }
 
@@ -47,7 +98,60 @@ impl Default for WriteBatchOptions {
     fn default() -> Self {
         Self {
             max_batch_size: 1 << 12,
-            write_sync: true,
+            write_sync: false,
         }
     }
 }
+
+#[derive(Clone, Default)]
+pub struct IteratorOptions {
+    // only iterate keys starting with it, empty means all keys
+    pub prefix: Vec<u8>,
+    // iterate keys in descending order
+    pub reverse: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoType {
+    // positional read and write syscalls
+    StandardFIO,
+    // read only memory map, used for immutable files
+    MemoryMap,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncPolicy {
+    // every write is synced before it returns, concurrent writers share one sync
+    Always,
+    // sync once this many bytes have been written since the last sync
+    EveryBytes(usize),
+    // a background thread syncs the active file at this interval
+    Interval(Duration),
+    // only rotations, `sync` and `close` sync the active file
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecoveryMode {
+    // a torn write at the tail of the active file is truncated,
+    // a bad record anywhere else fails the open with `Error::Corrupted`
+    TruncateTail,
+    // like `TruncateTail`, but a bad record in the middle of a file is
+    // logged and skipped up to the next valid record
+    SkipCorrupted,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IndexType {
+    // lock free ordered map
+    SkipList,
+    // ordered map behind a read write lock
+    BTree,
+    // concurrent hash map, point lookups only so iterators and transactions are not supported
+    HashMap,
+    // adaptive radix tree, compact for keys sharing long prefixes
+    Art,
+    // b+tree in a file under the db path, memory is bounded by its page cache
+    // and a clean close spares the next open from replaying the data files
+    BPlusTree,
+}
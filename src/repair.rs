@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io,
+};
+
+use fs4::FileExt;
+
+use crate::{
+    consts::{BPTREE_INDEX_FILE_NAME, FILE_LOCK},
+    data::{
+        datafile::DataFile,
+        log_record::{BatchState, Record, RecordPosition, RecordType},
+    },
+    error::{Error, Result},
+    key::Key,
+    merge::MergeEngine,
+    options::{check_options, BitcaskOptions, IoType},
+    storage::Bitcask,
+    utils::{get_merge_path, now_millis},
+};
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RepairReport {
+    // live records written to the rebuilt files
+    pub records: u64,
+    // bytes that held no valid record
+    pub corrupted_bytes: u64,
+    // records of batches whose finish mark was never written
+    pub uncommitted_records: u64,
+}
+
+impl Bitcask {
+    // rebuild a closed database from the records that still decode. bad spans are skipped
+    // up to the next valid record, the live records are rewritten like a merge does
+    pub fn repair(opts: BitcaskOptions) -> Result<RepairReport> {
+        check_options(&opts)?;
+        let path = &opts.db_path;
+
+        let lock_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(FILE_LOCK))?;
+        FileExt::try_lock_exclusive(&lock_file).map_err(|_| Error::DatabaseLocked)?;
+
+        // finish an interrupted merge first, its files are scanned like the others
+        Self::load_merge_file(path)?;
+
+        let index_file = path.join(BPTREE_INDEX_FILE_NAME);
+        if index_file.is_file() {
+            fs::remove_file(index_file)?;
+        }
+
+        let ids = Self::load_data_file_ids(path)?;
+        let mut files = HashMap::new();
+        for id in ids.iter() {
+            files.insert(
+                *id,
+                DataFile::new_with_io_type(path, *id, IoType::MemoryMap)?,
+            );
+        }
+
+        let mut report = RepairReport::default();
+
+        // latest position of every live key, batches only count once finished
+        let mut index: HashMap<Key, RecordPosition> = HashMap::new();
+        let mut batches: HashMap<u64, Vec<(Key, RecordType, RecordPosition)>> = HashMap::new();
+        for id in ids.iter() {
+            let file = &files[id];
+            let file_size = file.size()?;
+
+            let mut offset = 0;
+            loop {
+                let record = match file.read_record(offset) {
+                    Ok(record) => record,
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(Error::Corrupted { .. }) => {
+                        let next = file.next_valid_record(offset + 1)?;
+                        let end = next.unwrap_or(file_size);
+                        log::warn!(
+                            "repair: drop {} bytes of data file {} at offset {}",
+                            end - offset,
+                            id,
+                            offset
+                        );
+                        report.corrupted_bytes += end - offset;
+
+                        match next {
+                            Some(next) => {
+                                offset = next;
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let pos = RecordPosition::new(*id, offset, record.size() as u32);
+                offset += record.size() as u64;
+
+                let key = record.key().to_vec();
+                match record.batch_state {
+                    BatchState::Enable(seq) => {
+                        batches
+                            .entry(seq)
+                            .or_default()
+                            .push((key, record.record_type, pos));
+                    }
+                    BatchState::Finish(seq) => {
+                        for (key, record_type, pos) in batches.remove(&seq).unwrap_or_default() {
+                            apply(&mut index, key, record_type, pos);
+                        }
+                    }
+                    BatchState::Disable | BatchState::Txn(_) => {
+                        apply(&mut index, key, record.record_type, pos)
+                    }
+                    BatchState::TxnCommit(_) | BatchState::Snapshot => {}
+                }
+            }
+        }
+        report.uncommitted_records = batches.values().map(|batch| batch.len() as u64).sum();
+
+        // rewrite in file order, the rebuilt files replace every current one
+        let mut positions: Vec<RecordPosition> = index.into_values().collect();
+        positions.sort_unstable_by_key(|pos| (pos.file_id, pos.offset));
+
+        let merge_path = get_merge_path(path);
+        if merge_path.is_dir() {
+            fs::remove_dir_all(&merge_path)?;
+        }
+        fs::create_dir_all(&merge_path)?;
+
+        let merge_engine = MergeEngine::new(&merge_path, opts.max_file_size)?;
+        let mut hint_file = DataFile::hint_file(&merge_path)?;
+
+        let now = now_millis();
+        for pos in positions {
+            let mut record = files[&pos.file_id]
+                .read_record_with_size(pos.offset, pos.size as u64)?
+                .to_record();
+            if record.is_expired(now) {
+                continue;
+            }
+
+            record.disable_batch();
+            let merge_pos = merge_engine.append_record(&record)?;
+
+            let mut hint = Record::normal(record.key, merge_pos.encode());
+            hint.expire_at = record.expire_at;
+            hint_file.write_record(&hint)?;
+
+            report.records += 1;
+        }
+
+        hint_file.sync()?;
+        merge_engine.sync()?;
+
+        let merged_file_num = merge_engine.active_file.read().id + 1;
+        drop(merge_engine);
+        drop(files);
+
+        let next_file_id = ids.last().map_or(0, |id| id + 1).max(merged_file_num);
+        let mut merge_file = DataFile::merge_file(&merge_path)?;
+        merge_file.write_record(&Record::merge_finished(next_file_id, merged_file_num))?;
+        merge_file.sync()?;
+
+        Self::load_merge_file(path)?;
+
+        // an empty active file after the merged ones, as a merge leaves it
+        DataFile::new(path, next_file_id)?;
+
+        Ok(report)
+    }
+}
+
+fn apply(
+    index: &mut HashMap<Key, RecordPosition>,
+    key: Key,
+    record_type: RecordType,
+    pos: RecordPosition,
+) {
+    match record_type {
+        RecordType::Normal => {
+            index.insert(key, pos);
+        }
+        RecordType::Deleted => {
+            index.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::fs::FileExt};
+
+    use crate::{
+        options::WriteBatchOptions,
+        utils::{get_data_file_path, get_data_hint_file_path, TestDir},
+        verify::verify,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_repair() -> Result<()> {
+        let path = TestDir::new("bitcask_repair");
+        let ops = BitcaskOptions {
+            max_file_size: 4 << 10,
+            ..path.options()
+        };
+
+        let (pos, active_id) = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:032}", i))?;
+            }
+            for i in 0..100 {
+                bitcask.delete(format!("{:04}", i))?;
+            }
+            let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+            batch.put("batch", "x")?;
+            batch.commit()?;
+
+            let pos = bitcask.get_index(b"0150").get(b"0150").unwrap();
+            let active_id = bitcask.active_file.read().id;
+            bitcask.close()?;
+            (pos, active_id)
+        };
+
+        // a flipped byte in a sealed file and a batch cut short in the active file
+        let file = OpenOptions::new()
+            .write(true)
+            .open(get_data_file_path(&path, pos.file_id))?;
+        file.write_at(&[0xff], pos.offset + pos.size as u64 - 5)?;
+        fs::remove_file(get_data_hint_file_path(&path, pos.file_id))?;
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(get_data_file_path(&path, active_id))?;
+        let mut record = Record::normal("unfinished".into(), "x".into());
+        record.enable_batch(100);
+        file.write_all(&record.encode())?;
+
+        assert!(matches!(
+            Bitcask::open(ops.clone()),
+            Err(Error::Corrupted { .. })
+        ));
+
+        let report = Bitcask::repair(ops.clone())?;
+        assert_eq!(200, report.records);
+        assert_eq!(pos.size as u64, report.corrupted_bytes);
+        assert_eq!(1, report.uncommitted_records);
+        assert!(verify(&path)?.is_ok());
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(200, bitcask.stat()?.key_num);
+        assert_eq!(0, bitcask.stat()?.reclaimable_size);
+        assert!(matches!(bitcask.get("0150"), Err(Error::KeyNotFound)));
+        assert!(matches!(bitcask.get("0050"), Err(Error::KeyNotFound)));
+        assert!(matches!(bitcask.get("unfinished"), Err(Error::KeyNotFound)));
+        assert_eq!(format!("{:032}", 151).into_bytes(), bitcask.get("0151")?);
+        assert_eq!(b"x".to_vec(), bitcask.get("batch")?);
+
+        // the repaired database keeps working
+        bitcask.put("0150", "again")?;
+        bitcask.merge()?;
+        assert_eq!(b"again".to_vec(), bitcask.get("0150")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_snapshot_copies() -> Result<()> {
+        let path = TestDir::new("bitcask_repair_snapshot");
+        let ops = path.options();
+
+        // the merge writes the old value a snapshot sees after the live one
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            bitcask.put("a", "old")?;
+            let snapshot = bitcask.snapshot();
+            bitcask.put("a", "new")?;
+            bitcask.merge()?;
+            assert_eq!(b"old".to_vec(), snapshot.get("a")?);
+            drop(snapshot);
+            bitcask.close()?;
+        }
+
+        let report = Bitcask::repair(ops.clone())?;
+        assert_eq!(1, report.records);
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"new".to_vec(), bitcask.get("a")?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    data::datafile::DataFile,
+    error::{Error, Result},
+    options::{check_options, BitcaskOptions},
+    storage::{Bitcask, PendingBatches},
+};
+
+impl Bitcask {
+    // a read only instance over the files of a primary running in another process.
+    // it takes no lock and only sees what the primary had written when it was opened,
+    // `catch_up` replays what came after. files replaced by a merge of the primary
+    // stay readable until the secondary is reopened
+    pub fn open_secondary(
+        primary_path: impl AsRef<Path>,
+        opts: BitcaskOptions,
+    ) -> Result<Arc<Self>> {
+        let opts = BitcaskOptions {
+            db_path: primary_path.as_ref().to_path_buf(),
+            read_only: true,
+            ..opts
+        };
+        check_options(&opts)?;
+
+        Self::load(opts, None, true)
+    }
+
+    // index the records appended to the primary since the last catch up,
+    // including the ones of data files it has rotated to
+    pub fn catch_up(&self) -> Result<()> {
+        let pending = self.secondary.as_ref().ok_or(Error::Unsupported(
+            "catch up of an instance that is not a secondary",
+        ))?;
+        let mut batch_map = pending.lock();
+
+        let active_file_id = self.active_file.read().id;
+        let new_file_ids: Vec<u32> = Self::load_data_file_ids(&self.opts.db_path)?
+            .into_iter()
+            .filter(|id| *id > active_file_id)
+            .collect();
+
+        // files listed after the active one exist, so the primary is done with it.
+        // they are read through file handles, the primary may still truncate a torn tail
+        self.replay_active_file(&mut batch_map)?;
+
+        for id in new_file_ids {
+            let new_file = DataFile::open_read_only(&self.opts.db_path, id)?;
+
+            let mut active_file = self.active_file.write();
+            let sealed = std::mem::replace(&mut *active_file, new_file);
+            self.old_files.write().insert(sealed.id, sealed);
+            drop(active_file);
+
+            self.replay_active_file(&mut batch_map)?;
+        }
+
+        Ok(())
+    }
+
+    // a torn tail is a write still in progress, the next catch up goes on from it
+    fn replay_active_file(&self, batch_map: &mut PendingBatches) -> Result<()> {
+        let (file_id, offset) = {
+            let active_file = self.active_file.read();
+            (active_file.id, active_file.write_offset)
+        };
+
+        let end = self.update_index_from_datafile(file_id, offset, batch_map)?;
+        self.active_file.write().write_offset = end;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use crate::{data::log_record::Record, options::WriteBatchOptions, utils::get_data_file_path};
+
+    use super::*;
+
+    #[test]
+    fn test_secondary() -> Result<()> {
+        let path = "/tmp/bitcask_secondary";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let primary = Bitcask::open(ops.clone())?;
+        for i in 0..100 {
+            primary.put(format!("{:04}", i), format!("{:04}", i))?;
+        }
+
+        // the primary keeps its lock
+        let secondary = Bitcask::open_secondary(path, BitcaskOptions::default())?;
+        assert_eq!(100, secondary.stat()?.key_num);
+        assert!(matches!(secondary.put("foo", "bar"), Err(Error::ReadOnly)));
+        assert!(matches!(primary.catch_up(), Err(Error::Unsupported(_))));
+
+        for i in 100..300 {
+            primary.put(format!("{:04}", i), format!("{:04}", i))?;
+        }
+        for i in 0..50 {
+            primary.delete(format!("{:04}", i))?;
+        }
+        let mut batch = primary.new_batch_write(WriteBatchOptions::default())?;
+        batch.put("batch", "x")?;
+        batch.commit()?;
+        assert!(secondary.get("0200").is_err());
+
+        secondary.catch_up()?;
+        assert_eq!(251, secondary.stat()?.key_num);
+        assert!(secondary.stat()?.data_file_num > 1);
+        assert!(secondary.get("0010").is_err());
+        assert_eq!(b"0299".to_vec(), secondary.get("0299")?);
+        assert_eq!(b"x".to_vec(), secondary.get("batch")?);
+
+        // a batch is only applied once its finish mark shows up
+        let active_file_id = primary.active_file.read().id;
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(get_data_file_path(path, active_file_id))?;
+        let mut record = Record::normal("pending".into(), "x".into());
+        record.enable_batch(100);
+        file.write_all(&record.encode())?;
+
+        secondary.catch_up()?;
+        assert!(secondary.get("pending").is_err());
+
+        file.write_all(&Record::batch_finished(100).encode())?;
+        secondary.catch_up()?;
+        assert_eq!(b"x".to_vec(), secondary.get("pending")?);
+
+        // a write in progress ends the data, a later catch up reads it whole
+        let encoded = Record::normal("torn".into(), "x".into()).encode();
+        file.write_all(&encoded[..10])?;
+        secondary.catch_up()?;
+        assert!(secondary.get("torn").is_err());
+
+        file.write_all(&encoded[10..])?;
+        secondary.catch_up()?;
+        assert_eq!(b"x".to_vec(), secondary.get("torn")?);
+
+        // the active file is read through a file handle, a truncation is no fault
+        let len = file.metadata()?.len();
+        file.set_len(len - encoded.len() as u64)?;
+        secondary.catch_up()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{atomic::Ordering, Arc},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    data::{
+        datafile::DataFile,
+        log_record::{BatchState, Record, RecordPosition, RecordType},
+    },
+    error::{Error, Result},
+    iterator::{prefix_range, Entry, Iter},
+    key::{check_key_valid, Key},
+    options::IteratorOptions,
+    storage::Bitcask,
+    utils::now_millis,
+};
+
+// what a snapshot needs besides the index
+#[derive(Default)]
+pub(crate) struct SnapshotState {
+    // position as of the snapshot of every key changed since, `None` if it did not exist
+    preimages: Mutex<BTreeMap<Key, Option<RecordPosition>>>,
+}
+
+// frozen view of the database at the time it was taken. it is cheap to take, every
+// later index change saves the position it replaces for as long as the snapshot lives
+pub struct Snapshot<'a> {
+    storage: &'a Bitcask,
+    state: Arc<SnapshotState>,
+}
+
+// snapshot entries come from the live index, or from the preimages once they changed
+pub struct SnapshotIter<'a> {
+    iter: Iter<'a>,
+    state: &'a SnapshotState,
+    range: (Bound<Key>, Bound<Key>),
+    reverse: bool,
+    last: Option<Key>,
+}
+
+impl Bitcask {
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        let state = Arc::new(SnapshotState::default());
+
+        // waits for index changes in progress, a batch is seen whole or not at all
+        self.snapshots.write().push(state.clone());
+
+        Snapshot {
+            storage: self,
+            state,
+        }
+    }
+
+    // save the position of `key` in every live snapshot before the index changes it.
+    // callers hold the snapshots lock until the change is done
+    pub(crate) fn save_preimages(&self, snapshots: &[Arc<SnapshotState>], key: &[u8]) {
+        if snapshots.is_empty() {
+            return;
+        }
+
+        let pos = self.get_index(key).get(key);
+        for snapshot in snapshots {
+            snapshot.preimages.lock().entry(key.to_vec()).or_insert(pos);
+        }
+    }
+
+    pub(crate) fn get_from_snapshot(&self, state: &SnapshotState, key: &[u8]) -> Result<Vec<u8>> {
+        // merge moves the files only while no snapshot reads
+        let _snapshots = self.snapshots.read_recursive();
+
+        // the index first, a change after it has saved its preimage by then
+        let pos = self.get_index(key).get(key);
+        let pos = match state.preimages.lock().get(key) {
+            Some(preimage) => *preimage,
+            None => pos,
+        }
+        .ok_or(Error::KeyNotFound)?;
+
+        let record = self.get_record_with_pos(pos)?;
+        if record.record_type == RecordType::Deleted || record.is_expired(now_millis()) {
+            return Err(Error::KeyNotFound);
+        }
+
+        Ok(record.value().to_vec())
+    }
+
+    // copy the records in `merge_files` only live snapshots still see into the merge output.
+    // they get no hints and are marked so that no scan of the files indexes them
+    pub(crate) fn copy_snapshot_records(
+        &self,
+        snapshots: &[Arc<SnapshotState>],
+        merge_files: &[DataFile],
+        moved: &mut HashMap<(u32, u64), RecordPosition>,
+        mut append: impl FnMut(&Record) -> Result<RecordPosition>,
+    ) -> Result<()> {
+        let files: HashMap<u32, &DataFile> =
+            merge_files.iter().map(|file| (file.id, file)).collect();
+
+        for snapshot in snapshots {
+            for pos in snapshot.preimages.lock().values().flatten() {
+                let Some(file) = files.get(&pos.file_id) else {
+                    continue;
+                };
+                if moved.contains_key(&(pos.file_id, pos.offset)) {
+                    continue;
+                }
+
+                let mut record = file
+                    .read_record_with_size(pos.offset, pos.size as u64)?
+                    .to_record();
+                record.batch_state = BatchState::Snapshot;
+                let merge_pos = append(&record)?;
+
+                moved.insert((pos.file_id, pos.offset), merge_pos);
+            }
+        }
+
+        Ok(())
+    }
+
+    // point the preimages at the copies once the merged files are in place
+    pub(crate) fn move_snapshot_records(
+        &self,
+        snapshots: &[Arc<SnapshotState>],
+        moved: HashMap<(u32, u64), RecordPosition>,
+    ) {
+        for snapshot in snapshots {
+            for pos in snapshot.preimages.lock().values_mut().flatten() {
+                if let Some(merge_pos) = moved.get(&(pos.file_id, pos.offset)) {
+                    *pos = *merge_pos;
+                }
+            }
+        }
+
+        let copied: u32 = moved.values().map(|pos| pos.size).sum();
+        self.reclaimable
+            .fetch_add(copied as usize, Ordering::SeqCst);
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+        let key = key.as_ref().to_vec();
+        check_key_valid(&key)?;
+
+        self.storage.get_from_snapshot(&self.state, &key)
+    }
+
+    pub fn iter(&self, opts: IteratorOptions) -> Result<SnapshotIter<'_>> {
+        let range = prefix_range(&opts.prefix);
+
+        Ok(SnapshotIter {
+            iter: self.storage.iter(opts.clone())?,
+            state: &self.state,
+            range,
+            reverse: opts.reverse,
+            last: None,
+        })
+    }
+
+    // keys starting with `prefix`, in ascending order
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<SnapshotIter<'_>> {
+        self.iter(IteratorOptions {
+            prefix: prefix.as_ref().to_vec(),
+            ..Default::default()
+        })
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.storage
+            .snapshots
+            .write()
+            .retain(|state| !Arc::ptr_eq(state, &self.state));
+    }
+}
+
+impl<'a> SnapshotIter<'a> {
+    // the next key after `last` that existed when the snapshot was taken and has changed since
+    fn next_preimage(&self) -> Option<(Key, RecordPosition)> {
+        let (mut start, mut end) = self.range.clone();
+        if let Some(last) = self.last.clone() {
+            match self.reverse {
+                true => end = Bound::Excluded(last),
+                false => start = Bound::Excluded(last),
+            }
+        }
+
+        let preimages = self.state.preimages.lock();
+        let mut range = preimages
+            .range((start, end))
+            .filter_map(|(key, pos)| pos.map(|pos| (key, pos)));
+        let (key, pos) = match self.reverse {
+            true => range.next_back()?,
+            false => range.next()?,
+        };
+
+        Some((key.clone(), pos))
+    }
+}
+
+impl<'a> Iterator for SnapshotIter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // the preimages are looked up every time, keys keep changing while iterating
+            let live = self.iter.peek().cloned();
+            let preimage = self.next_preimage();
+
+            let from_live = match (&live, &preimage) {
+                (None, None) => return None,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some((live, _)), Some((preimage, _))) => match self.reverse {
+                    true => live > preimage,
+                    false => live < preimage,
+                },
+            };
+
+            let (key, pos) = match from_live {
+                true => live?,
+                false => preimage?,
+            };
+            if self.iter.peek().is_some_and(|(live, _)| *live == key) {
+                self.iter.next();
+            }
+            self.last = Some(key.clone());
+
+            let pos = match self.state.preimages.lock().get(&key) {
+                // created after the snapshot was taken
+                Some(None) => continue,
+                Some(Some(preimage)) => *preimage,
+                None => pos,
+            };
+
+            return Some(Entry {
+                storage: self.iter.storage,
+                key,
+                pos,
+                snapshot: Some(self.state),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::options::{BitcaskOptions, WriteBatchOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let path = "/tmp/bitcask_snapshot";
+        let _ = std::fs::remove_dir_all(path);
+        let bitcask = Bitcask::open(BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        })?;
+
+        for i in 0..300 {
+            bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+        }
+        bitcask.delete("0000")?;
+
+        let snapshot = bitcask.snapshot();
+        for i in 0..100 {
+            bitcask.put(format!("{:04}", i), "changed")?;
+        }
+        for i in 100..200 {
+            bitcask.delete(format!("{:04}", i))?;
+        }
+        let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+        batch.put("new", "x")?;
+        batch.commit()?;
+
+        assert!(snapshot.get("0000").is_err());
+        assert_eq!(b"0050".to_vec(), snapshot.get("0050")?);
+        assert_eq!(b"0150".to_vec(), snapshot.get("0150")?);
+        assert!(snapshot.get("new").is_err());
+        assert_eq!(b"changed".to_vec(), bitcask.get("0050")?);
+        assert!(bitcask.get("0150").is_err());
+
+        let keys: Vec<Key> = (1..300).map(|i| format!("{:04}", i).into_bytes()).collect();
+        let entries: Vec<(Key, Vec<u8>)> = snapshot
+            .iter(IteratorOptions::default())?
+            .map(|entry| Ok((entry.key().to_vec(), entry.value()?)))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            keys,
+            entries.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>()
+        );
+        assert!(entries.iter().all(|(k, v)| k == v));
+
+        let reversed: Vec<Key> = snapshot
+            .iter(IteratorOptions {
+                prefix: b"01".to_vec(),
+                reverse: true,
+            })?
+            .map(|entry| entry.key().to_vec())
+            .collect();
+        assert_eq!(
+            keys[99..199].iter().rev().cloned().collect::<Vec<_>>(),
+            reversed
+        );
+
+        // merge keeps what the snapshot sees readable
+        bitcask.merge()?;
+        assert_eq!(b"0050".to_vec(), snapshot.get("0050")?);
+        assert_eq!(b"0150".to_vec(), snapshot.get("0150")?);
+        assert_eq!(b"0250".to_vec(), snapshot.get("0250")?);
+        assert_eq!(299, snapshot.scan_prefix("")?.count());
+        assert_eq!(b"changed".to_vec(), bitcask.get("0050")?);
+
+        drop(snapshot);
+        assert!(bitcask.snapshots.read().is_empty());
+        assert_eq!(201, bitcask.scan_prefix("")?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_during_merge() -> Result<()> {
+        let path = "/tmp/bitcask_snapshot_during_merge";
+        let _ = std::fs::remove_dir_all(path);
+        let opts = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+        let bitcask = Bitcask::open(opts.clone())?;
+
+        for i in 0..100 {
+            bitcask.put(format!("{:04}", i), "0")?;
+        }
+
+        // snapshots taken and outdated while merges copy and swap the files
+        let writer = {
+            let bitcask = bitcask.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut values = vec![0; 100];
+                let mut snapshots = VecDeque::new();
+                for round in 1..500 {
+                    let i = round % 100;
+                    snapshots.push_back((bitcask.snapshot(), i, values[i]));
+                    bitcask.put(format!("{:04}", i), format!("{}", round))?;
+                    values[i] = round;
+
+                    for (snapshot, i, value) in snapshots.iter() {
+                        let expected = format!("{}", value).into_bytes();
+                        assert_eq!(expected, snapshot.get(format!("{:04}", i))?);
+                    }
+                    if snapshots.len() > 10 {
+                        snapshots.pop_front();
+                    }
+                }
+                Ok(())
+            })
+        };
+
+        while !writer.is_finished() {
+            bitcask.merge()?;
+        }
+        writer.join().unwrap()?;
+
+        bitcask.merge()?;
+        assert_eq!(b"450".to_vec(), bitcask.get("0050")?);
+        bitcask.close()?;
+        drop(bitcask);
+
+        let bitcask = Bitcask::open(opts)?;
+        assert_eq!(100, bitcask.stat()?.key_num);
+        assert_eq!(b"450".to_vec(), bitcask.get("0050")?);
+
+        Ok(())
+    }
+}
@@ -1,33 +1,43 @@
 use std::{
     collections::HashMap,
     fs::{self, File, OpenOptions},
+    io::{self, Write},
     path::Path,
     sync::{
         atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use anyhow::Result;
 use fs4::FileExt;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 
 use crate::{
-    consts::{DATA_FILE_SUFFIX, FILE_LOCK},
+    consts::{
+        BPTREE_INDEX_FILE_NAME, DATA_FILE_SUFFIX, FILE_LOCK, HINT_FILE_NAME, MERGE_FILE_NAME,
+    },
     data::{
         datafile::DataFile,
-        log_record::{Record, RecordPosition, RecordReader, RecordType},
+        log_record::{BatchState, Record, RecordPosition, RecordReader, RecordType},
     },
-    index::{new_indexer, Indexer},
-    key::check_key_valid,
-    options::{check_options, BitcaskOptions},
+    error::{Error, Result},
+    flusher::Flusher,
+    group_commit::CommitQueue,
+    index::{new_indexer, IndexState, Indexer},
+    key::{check_key_size, check_key_valid},
+    merge::{MergeMark, MergeWorker},
+    options::{check_options, BitcaskOptions, IndexType, IoType, RecoveryMode, SyncPolicy},
+    snapshot::SnapshotState,
+    subscribe::{ChangeEvent, Subscriber},
     transaction::{Transaction, TxnSearchType},
-    utils::get_merge_path,
+    utils::{dir_disk_size, get_data_file_path, get_data_hint_file_path, now_millis},
 };
 
 #[derive(Clone, Copy, Debug)]
 pub struct BitcaskState {
     pub data_file_num: u32,
+    // expired keys count until a merge or the next open drops them
     pub key_num: u32,
 
     // 可回收空间
@@ -44,73 +54,148 @@ pub struct Bitcask {
 
     pub(crate) active_file: RwLock<DataFile>,
     pub(crate) old_files: RwLock<HashMap<u32, DataFile>>,
+    // hints of the active file, written out when it is sealed
+    pub(crate) active_hints: Mutex<Vec<u8>>,
+
+    // writers sharing one sync with `SyncPolicy::Always`
+    pub(crate) commit_queue: CommitQueue,
 
     pub(crate) batch_lock: Mutex<()>,
     pub(crate) batch_seq: AtomicU64,
 
     pub(crate) merge_lock: Mutex<()>,
-
-    pub(crate) lock_file: File,
+    // live snapshots, index changes are made under its read lock
+    pub(crate) snapshots: RwLock<Vec<Arc<SnapshotState>>>,
+    // receivers of committed changes
+    pub(crate) subscribers: Mutex<Vec<Subscriber>>,
+    // one per index shard, taken before the subscribers lock
+    pub(crate) shard_locks: Vec<Mutex<()>>,
+
+    // a secondary never locks the primary
+    pub(crate) lock_file: Option<File>,
+    // bytes appended since the last sync
     pub(crate) bytes_written: AtomicUsize,
     pub(crate) reclaimable: AtomicUsize,
+
+    pub(crate) merge_worker: Mutex<Option<MergeWorker>>,
+    pub(crate) flusher: Mutex<Option<Flusher>>,
+
+    // batches of the primary still waiting for their finish mark, only set for a secondary
+    pub(crate) secondary: Option<Mutex<PendingBatches>>,
 }
 
+// records of unfinished batches by their sequence number
+pub(crate) type PendingBatches = HashMap<u64, Vec<(Record, RecordPosition)>>;
+
 impl Bitcask {
-    pub fn open(opts: BitcaskOptions) -> Result<Self> {
+    pub fn open(opts: BitcaskOptions) -> Result<Arc<Self>> {
         check_options(&opts)?;
 
-        fs::create_dir_all(&opts.db_path)
-            .map_err(|_| anyhow::Error::msg("create db path error!"))?;
+        // a read only open never creates anything
+        if !opts.read_only {
+            fs::create_dir_all(&opts.db_path)?;
+        }
 
-        // lock file
+        // lock file, shared by any number of read only opens
         let lock_file = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
+            .write(!opts.read_only)
+            .create(!opts.read_only)
             .truncate(false)
-            .open(opts.db_path.join(FILE_LOCK))
-            .map_err(|_| anyhow::Error::msg("try to open lock file error!"))?;
+            .open(opts.db_path.join(FILE_LOCK))?;
 
-        lock_file
-            .lock_exclusive()
-            .map_err(|_| anyhow::Error::msg("try to lock lock file error!"))?;
+        let locked = match opts.read_only {
+            true => FileExt::try_lock_shared(&lock_file),
+            false => lock_file.try_lock_exclusive(),
+        };
+        locked.map_err(|_| Error::DatabaseLocked)?;
+
+        // handle merge path, the persisted index has never seen the moved files.
+        // it also goes stale once another index type writes to the database.
+        // a finished merge is left to the next writable open, the files it replaces are intact
+        if !opts.read_only {
+            let merged = Self::load_merge_file(&opts.db_path)?.is_some();
+            if merged || opts.index_type != IndexType::BPlusTree {
+                let index_file = opts.db_path.join(BPTREE_INDEX_FILE_NAME);
+                if index_file.is_file() {
+                    fs::remove_file(index_file)?;
+                }
+            }
+        }
 
-        // handle merge path
-        Self::load_merge_file(&opts.db_path)?;
+        Self::load(opts, Some(lock_file), false)
+    }
 
+    // build the engine from the data files under the db path
+    pub(crate) fn load(
+        opts: BitcaskOptions,
+        lock_file: Option<File>,
+        secondary: bool,
+    ) -> Result<Arc<Self>> {
         let mut datafile_ids = Self::load_data_file_ids(&opts.db_path)?;
 
-        let active_file = datafile_ids
-            .pop()
-            .map(|id| DataFile::new(&opts.db_path, id))
-            .unwrap_or(DataFile::new(&opts.db_path, 0))
-            .unwrap();
+        // all files are memory mapped while loading the index, but the active file of a
+        // primary a secondary follows
+        let active_file = match datafile_ids.pop() {
+            Some(id) if secondary => DataFile::open_read_only(&opts.db_path, id)?,
+            Some(id) => DataFile::new_with_io_type(&opts.db_path, id, IoType::MemoryMap)?,
+            None if opts.read_only => return Err(Error::DataFileNotFound(0)),
+            None => DataFile::new(&opts.db_path, 0)?,
+        };
         let active_file_id = active_file.id;
 
         let old_files: Vec<DataFile> = datafile_ids
             .iter()
-            .map(|id| DataFile::new(&opts.db_path, *id).unwrap())
+            .map(|id| DataFile::new_with_io_type(&opts.db_path, *id, IoType::MemoryMap).unwrap())
             .collect();
 
         let old_files = datafile_ids.iter().copied().zip(old_files).collect();
 
+        let shard_locks = (0..opts.index_num).map(|_| Mutex::new(())).collect();
+
         let mut bitcask = Self {
-            indexs: new_indexer(opts.index_num),
+            indexs: new_indexer(&opts)?,
             file_ids: datafile_ids,
             opts,
             active_file: RwLock::new(active_file),
             old_files: RwLock::new(old_files),
+            active_hints: Mutex::new(Vec::new()),
+            commit_queue: CommitQueue::default(),
             batch_lock: Mutex::new(()),
             batch_seq: AtomicU64::new(1),
             merge_lock: Mutex::new(()),
+            snapshots: RwLock::new(Vec::new()),
+            subscribers: Mutex::new(Vec::new()),
+            shard_locks,
             lock_file,
             bytes_written: AtomicUsize::new(0),
             reclaimable: AtomicUsize::new(0),
+            merge_worker: Mutex::new(None),
+            flusher: Mutex::new(None),
+            secondary: secondary.then(Default::default),
         };
 
         bitcask.file_ids.push(active_file_id);
 
-        bitcask.load_index()?;
+        match bitcask.indexs[0].saved_state() {
+            Some(state) => bitcask.restore_state(state)?,
+            None => bitcask.load_index()?,
+        }
+        // the active file is no longer mapped when its torn tail is truncated,
+        // a read only database stays mapped
+        if !bitcask.opts.read_only {
+            bitcask.reset_io_type()?;
+        }
+        bitcask.load_active_hints()?;
+
+        let bitcask = Arc::new(bitcask);
+        if bitcask.opts.auto_merge {
+            let worker = MergeWorker::spawn(Arc::downgrade(&bitcask), bitcask.opts.merge_interval);
+            *bitcask.merge_worker.lock() = Some(worker);
+        }
+        if let SyncPolicy::Interval(interval) = bitcask.opts.sync_policy {
+            *bitcask.flusher.lock() = Some(Flusher::spawn(Arc::downgrade(&bitcask), interval));
+        }
 
         Ok(bitcask)
     }
@@ -120,24 +205,48 @@ impl Bitcask {
         let value = value.as_ref().to_vec();
 
         check_key_valid(&key.to_vec())?;
+        check_key_size(&key, self.opts.index_type)?;
+
+        self.write_value(Record::normal(key, value))
+    }
+
+    // the key is treated as missing once `ttl` has passed. iterators and `stat` only
+    // see an expired key go with the next merge or open, its value reads as missing
+    pub fn put_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = key.as_ref().to_vec();
+        let value = value.as_ref().to_vec();
+
+        check_key_valid(&key)?;
+        check_key_size(&key, self.opts.index_type)?;
+
+        // a ttl too long to count in milliseconds never runs out
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expire_at = now_millis().saturating_add(ttl);
+        self.write_value(Record::normal_with_expire(key, value, expire_at))
+    }
+
+    fn write_value(&self, record: Record) -> Result<()> {
+        // the log and the mem-index see the writes of a shard in the same order
+        let _shard = self.lock_shard(&record.key);
 
-        // construct record
-        let record = Record::normal(key, value);
         // write into wal
-        let pos = self.append_record(&record)?;
+        let (record, pos) = self.commit_record(record)?;
 
         // update mem-index
-        let pre_pos = self
-            .get_index(&record.key)
-            .put(record.key, pos)
-            .map_err(|_| anyhow::Error::msg("put: update mem-index error!"))?;
-
-        if let Some(pre_pos) = pre_pos {
-            self.reclaimable
-                .fetch_add(pre_pos.size as usize, Ordering::SeqCst);
-        }
+        self.apply_write(&record, pos)
+    }
 
-        Ok(())
+    // index a written record and send it to the subscribers, the caller holds its shard lock
+    pub(crate) fn apply_write(&self, record: &Record, pos: RecordPosition) -> Result<()> {
+        self.publish(
+            || self.update_index(record, pos),
+            |_| Some(ChangeEvent::write(record, pos)),
+        )
     }
 
     pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Vec<u8>> {
@@ -145,58 +254,124 @@ impl Bitcask {
         check_key_valid(&key)?;
 
         // acquire record
-        let pos = self
-            .get_index(&key)
-            .get(&key)
-            .ok_or(anyhow::Error::msg("get: key not found!"))?;
-        let record = self.get_record_with_pos(pos)?;
+        let pos = self.get_index(&key).get(&key).ok_or(Error::KeyNotFound)?;
+        let record = self.get_record_with_key(&key, pos)?;
 
         if let RecordType::Deleted = record.record_type {
-            return Err(anyhow::Error::msg("get: key has been deleted!"));
+            return Err(Error::KeyNotFound);
+        }
+
+        if record.is_expired(now_millis()) {
+            return Err(Error::KeyNotFound);
         }
 
         Ok(record.value().to_vec())
     }
 
-    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<()> {
+    // remaining time to live of `key`, `None` if it never expires
+    pub fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
         let key = key.as_ref().to_vec();
         check_key_valid(&key)?;
 
-        let index = self.get_index(&key);
-        if !index.exits(&key) {
-            return Ok(());
-        }
+        let pos = self.get_index(&key).get(&key).ok_or(Error::KeyNotFound)?;
+        let record = self.get_record_with_key(&key, pos)?;
 
-        let record = Record::deleted(key);
-        self.append_record(&record)?;
+        let now = now_millis();
+        match record.expire_at {
+            Some(_) if record.is_expired(now) => Err(Error::KeyNotFound),
+            Some(expire_at) => Ok(Some(Duration::from_millis(expire_at - now))),
+            None => Ok(None),
+        }
+    }
 
-        let pre_pos = index
-            .delete(&record.key)
-            .map_err(|_| anyhow::Error::msg("delete: remove key from mem-index error!"))?;
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref().to_vec();
+        check_key_valid(&key)?;
 
-        self.reclaimable
-            .fetch_add(pre_pos.size as usize, Ordering::SeqCst);
+        let _shard = self.lock_shard(&key);
+        if !self.get_index(&key).exits(&key) {
+            return Ok(());
+        }
 
-        Ok(())
+        let (record, pos) = self.commit_record(Record::deleted(key))?;
+        self.apply_write(&record, pos)
     }
 
     pub fn close(&self) -> Result<()> {
+        if let Some(worker) = self.merge_worker.lock().take() {
+            worker.stop();
+        }
+        if let Some(flusher) = self.flusher.lock().take() {
+            flusher.stop();
+        }
+
         self.sync()?;
-        self.lock_file.unlock()?;
+
+        let state = IndexState {
+            batch_seq: self.batch_seq.load(Ordering::SeqCst),
+            reclaimable: self.reclaimable.load(Ordering::SeqCst) as u64,
+        };
+        for index in self.indexs.iter() {
+            index.persist(state)?;
+        }
+
+        if let Some(lock_file) = &self.lock_file {
+            lock_file.unlock()?;
+        }
 
         Ok(())
     }
 
     pub fn sync(&self) -> Result<()> {
-        self.active_file.read().sync()
+        let active_file = self.active_file.read();
+        active_file.sync()?;
+        self.bytes_written.store(0, Ordering::SeqCst);
+
+        Ok(())
     }
 
     pub fn is_empty(&self) -> bool {
         self.indexs.iter().all(|index| index.is_empty())
     }
 
+    pub fn stat(&self) -> Result<BitcaskState> {
+        let data_file_num = self.old_files.read().len() as u32 + 1;
+        let key_num = self.indexs.iter().map(|index| index.len() as u32).sum();
+
+        Ok(BitcaskState {
+            data_file_num,
+            key_num,
+            reclaimable_size: self.reclaimable.load(Ordering::SeqCst),
+            disk_used: dir_disk_size(&self.opts.db_path)?,
+        })
+    }
+
     pub(crate) fn get_index(&self, key: &[u8]) -> Arc<dyn Indexer> {
-        self.indexs[key[0] as usize % self.opts.index_num as usize].clone()
+        self.indexs[self.shard(key)].clone()
+    }
+
+    fn shard(&self, key: &[u8]) -> usize {
+        key[0] as usize % self.opts.index_num as usize
+    }
+
+    // held by every writer of the shard from deciding on a write until it is indexed
+    pub(crate) fn lock_shard(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.shard_locks[self.shard(key)].lock()
+    }
+
+    // lock the shards of all `keys`, always in the same order
+    pub(crate) fn lock_shards<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut shards: Vec<usize> = keys.map(|key| self.shard(key)).collect();
+        shards.sort();
+        shards.dedup();
+
+        shards
+            .into_iter()
+            .map(|shard| self.shard_locks[shard].lock())
+            .collect()
     }
 
     #[cfg(test)]
@@ -206,9 +381,8 @@ impl Bitcask {
 }
 
 impl Bitcask {
-    fn load_data_file_ids(path: impl AsRef<Path>) -> Result<Vec<u32>> {
-        let dir = fs::read_dir(&path)
-            .map_err(|_| anyhow::Error::msg("load datafile ids, open dir error!"))?;
+    pub(crate) fn load_data_file_ids(path: impl AsRef<Path>) -> Result<Vec<u32>> {
+        let dir = fs::read_dir(&path)?;
 
         let datafile_names: Vec<String> = dir
             .filter_map(|f| f.ok())
@@ -227,7 +401,7 @@ impl Bitcask {
                 .unwrap()
                 .0
                 .parse::<u32>()
-                .map_err(|_| anyhow::Error::msg("parse filename error!"))?;
+                .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
 
             ids.push(id);
         }
@@ -237,29 +411,104 @@ impl Bitcask {
         Ok(ids)
     }
 
+    // the active file goes back to positional writes, sealed files follow the options
+    fn reset_io_type(&self) -> Result<()> {
+        self.active_file
+            .write()
+            .set_io_type(&self.opts.db_path, IoType::StandardFIO)?;
+
+        if self.opts.io_type != IoType::MemoryMap {
+            for file in self.old_files.write().values_mut() {
+                file.set_io_type(&self.opts.db_path, self.opts.io_type)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // the index was persisted at the last close, only the engine state is left to recover
+    fn restore_state(&self, state: IndexState) -> Result<()> {
+        self.batch_seq.store(state.batch_seq, Ordering::SeqCst);
+        self.reclaimable
+            .store(state.reclaimable as usize, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // hints of the records already in the active file, it also finds the write offset
+    // and truncates a torn write after it
+    fn load_active_hints(&self) -> Result<()> {
+        let active_file_id = self.active_file.read().id;
+
+        let mut hints = Vec::new();
+        let write_offset = self.for_each_record(active_file_id, 0, |record, pos| {
+            hints.extend(record.to_hint(pos).encode());
+            Ok(())
+        })?;
+
+        let mut active_file = self.active_file.write();
+        let file_size = active_file.size()?;
+        if write_offset < file_size && !self.opts.read_only {
+            log::warn!(
+                "truncate torn write of {} bytes at the tail of data file {} at offset {}",
+                file_size - write_offset,
+                active_file_id,
+                write_offset
+            );
+
+            let file = OpenOptions::new()
+                .write(true)
+                .open(get_data_file_path(&self.opts.db_path, active_file_id))?;
+            file.set_len(write_offset)?;
+            file.sync_all()?;
+        }
+
+        active_file.write_offset = write_offset;
+        *self.active_hints.lock() = hints;
+
+        Ok(())
+    }
+
     fn load_index(&self) -> Result<()> {
         self.load_index_from_hint_file()?;
         self.load_index_from_datafile()
     }
 
     fn load_index_from_hint_file(&self) -> Result<()> {
-        let hint_file = DataFile::hint_file(&self.opts.db_path)?;
+        let hint_file_path = self.opts.db_path.join(HINT_FILE_NAME);
+        if !hint_file_path.is_file() {
+            return Ok(());
+        }
+        // opened read only, the database may be on read only media
+        let hint_file = DataFile::from_path(hint_file_path)?;
 
+        let now = now_millis();
+        let mut indexed_size = 0;
         let mut offset = 0;
-        loop {
-            match hint_file.read_record(offset) {
-                Ok(record) => {
-                    self.get_index(record.key()).put(
-                        record.key().to_vec(),
-                        RecordPosition::decode(record.value()),
-                    )?;
-
-                    offset += record.size() as u64;
-                }
-                Err(_) => break,
+        while let Ok(record) = hint_file.read_record(offset) {
+            offset += record.size() as u64;
+
+            // expired records are not indexed
+            if record.record_type == RecordType::Deleted || record.is_expired(now) {
+                continue;
             }
+
+            let pos = RecordPosition::decode(record.value());
+            indexed_size += pos.size as u64;
+            self.get_index(record.key())
+                .put(record.key().to_vec(), pos)?;
         }
 
+        // the rest of the merged files is reclaimable, like expired records and the
+        // copies kept for snapshots
+        let next_file_id = MergeMark::read(&self.opts.db_path)?.next_file_id;
+        let mut merged_size = 0;
+        for id in self.file_ids.iter().filter(|id| **id < next_file_id) {
+            merged_size += fs::metadata(get_data_file_path(&self.opts.db_path, *id))?.len();
+        }
+        self.reclaimable
+            .fetch_add((merged_size - indexed_size) as usize, Ordering::SeqCst);
+
         Ok(())
     }
 
@@ -270,138 +519,318 @@ impl Bitcask {
 
         let mut merged = false;
         let mut next_file_id = 0;
-        let merge_file_path = get_merge_path(&self.opts.db_path);
+        let merge_file_path = self.opts.db_path.join(MERGE_FILE_NAME);
 
-        if merge_file_path.exists() && merge_file_path.is_file() {
-            let merge_file = DataFile::merge_file(&merge_file_path)?;
-            let record = merge_file.read_record(0)?;
-            let id_bytes = record.value().first_chunk::<4>().unwrap();
-            next_file_id = u32::from_be_bytes(*id_bytes);
+        if merge_file_path.is_file() {
+            next_file_id = MergeMark::read(&self.opts.db_path)?.next_file_id;
             merged = true;
         }
 
+        // a batch may be split by a file rotation, so pending batches
+        // are shared by all data files
+        let mut batch_map = HashMap::new();
+
         let (active_file_id, old_file_ids) = self.file_ids.split_last().unwrap();
         for id in old_file_ids.iter() {
             if merged && *id < next_file_id {
                 continue;
             }
 
-            self.update_index_from_datafile(*id)?;
+            self.update_index_from_datafile(*id, 0, &mut batch_map)?;
         }
 
         self.active_file.write().write_offset =
-            self.update_index_from_datafile(*active_file_id).unwrap();
+            self.update_index_from_datafile(*active_file_id, 0, &mut batch_map)?;
+
+        // a secondary may still see the primary finish them
+        if let Some(pending) = &self.secondary {
+            *pending.lock() = batch_map;
+            return Ok(());
+        }
+
+        // records of batches that never finished are garbage
+        let uncommitted: u32 = batch_map.values().flatten().map(|(_, pos)| pos.size).sum();
+        self.reclaimable
+            .fetch_add(uncommitted as usize, Ordering::SeqCst);
 
         Ok(())
     }
 
-    fn update_index_from_datafile(&self, file_id: u32) -> Result<u64> {
-        let mut offset = 0;
-        let mut batch_map = HashMap::<u64, Vec<Record>>::new();
+    // index the records of a data file from `offset` on, return the offset after the last one
+    pub(crate) fn update_index_from_datafile(
+        &self,
+        file_id: u32,
+        offset: u64,
+        batch_map: &mut PendingBatches,
+    ) -> Result<u64> {
         let mut current_seq = self.batch_seq.load(Ordering::SeqCst);
 
-        loop {
-            let (record_len, record) = match self.get_record_with_offset(file_id, offset) {
-                Ok(reader) => (reader.size(), reader.to_record()),
-                Err(_) => break,
-            };
-
-            let pos = RecordPosition::new(file_id, offset, record_len as u32);
-
+        let offset = self.for_each_record(file_id, offset, |record, pos| {
             match record.batch_state {
-                crate::data::log_record::BatchState::Enable(seq) => {
-                    batch_map.entry(seq).or_default().push(record);
+                BatchState::Enable(seq) => {
+                    batch_map.entry(seq).or_default().push((record, pos));
                 }
-                crate::data::log_record::BatchState::Finish(seq) => {
+                BatchState::Finish(seq) => {
+                    // records before the last merge were already rewritten without batch
                     batch_map
                         .remove(&seq)
-                        .expect("update index, batch write is none!")
+                        .unwrap_or_default()
                         .into_iter()
-                        .try_for_each(|record| self.update_index(&record, pos))?;
+                        .try_for_each(|(record, pos)| self.update_index(&record, pos))?;
+
+                    // the finish mark itself is never indexed
+                    self.reclaimable
+                        .fetch_add(pos.size as usize, Ordering::SeqCst);
 
                     if current_seq < seq {
                         current_seq = seq;
                     }
                 }
-                crate::data::log_record::BatchState::Disable => {
+                // versions of transactions that never committed are removed by the engine
+                BatchState::Disable | BatchState::Txn(_) => {
                     self.update_index(&record, pos)?;
                 }
+                // snapshots of a merge are gone once the database is reopened
+                BatchState::TxnCommit(_) | BatchState::Snapshot => {
+                    self.reclaimable
+                        .fetch_add(pos.size as usize, Ordering::SeqCst);
+                }
             }
 
-            offset += record_len as u64;
-        }
+            Ok(())
+        })?;
 
         self.batch_seq.store(current_seq, Ordering::SeqCst);
 
         Ok(offset)
     }
 
-    fn update_index(&self, record: &Record, pos: RecordPosition) -> Result<()> {
-        let index = self.get_index(&record.key);
+    // call `f` on every record of a data file from `offset` in order and return the offset
+    // after the last one. a whole sealed file with a hint file is read from its hints,
+    // their values are left empty
+    fn for_each_record(
+        &self,
+        file_id: u32,
+        offset: u64,
+        mut f: impl FnMut(Record, RecordPosition) -> Result<()>,
+    ) -> Result<u64> {
+        let sealed = self.active_file.read().id != file_id;
+
+        if sealed && offset == 0 && get_data_hint_file_path(&self.opts.db_path, file_id).is_file() {
+            match self.read_data_hints(file_id) {
+                Ok(hints) => {
+                    let mut end = 0;
+                    for (record, pos) in hints {
+                        end = pos.offset + pos.size as u64;
+                        f(record, pos)?;
+                    }
 
-        let position = match record.record_type {
-            crate::data::log_record::RecordType::Deleted => Some(index.delete(&record.key)?),
-            crate::data::log_record::RecordType::Normal => index.put(record.key.clone(), pos)?,
-        };
+                    return Ok(end);
+                }
+                Err(e) => log::warn!(
+                    "read hint file of data file {} error: {}, read the data file instead",
+                    file_id,
+                    e
+                ),
+            }
+        }
+
+        self.read_records(file_id, offset, f)
+    }
+
+    // call `f` on every record of a data file from `offset` in order, with their values.
+    // return the offset after the last one
+    pub(crate) fn read_records(
+        &self,
+        file_id: u32,
+        mut offset: u64,
+        mut f: impl FnMut(Record, RecordPosition) -> Result<()>,
+    ) -> Result<u64> {
+        let sealed = self.active_file.read().id != file_id;
+
+        loop {
+            let reader = match self.get_record_with_offset(file_id, offset) {
+                Ok(reader) => reader,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(Error::Corrupted { .. }) => {
+                    let next = self.with_data_file(file_id, |file| {
+                        self.skip_bad_record(file, offset, !sealed)
+                    })?;
+
+                    match next {
+                        Some(next) => {
+                            offset = next;
+                            continue;
+                        }
+                        None => break,
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+
+            let (record_len, record) = (reader.size(), reader.to_record());
+
+            let pos = RecordPosition::new(file_id, offset, record_len as u32);
+            f(record, pos)?;
+            offset += record_len as u64;
+        }
+
+        Ok(offset)
+    }
+
+    // records of a sealed data file read from its hint file, their values are left empty
+    fn read_data_hints(&self, file_id: u32) -> Result<Vec<(Record, RecordPosition)>> {
+        let hint_file = DataFile::from_path(get_data_hint_file_path(&self.opts.db_path, file_id))?;
 
-        if let Some(pos) = position {
-            self.reclaimable
-                .fetch_add(pos.size as usize, Ordering::SeqCst);
+        let mut hints = Vec::new();
+        let mut offset = 0;
+        loop {
+            let hint = match hint_file.read_record(offset) {
+                Ok(hint) => hint,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            offset += hint.size() as u64;
+
+            let pos = RecordPosition::decode(hint.value());
+            let mut record = hint.to_record();
+            record.value.clear();
+
+            hints.push((record, pos));
         }
 
-        Ok(())
+        Ok(hints)
     }
 
-    fn get_record_with_offset(&self, file_id: u32, offset: u64) -> Result<RecordReader> {
-        let active_file_id = {
-            let active_file = self.active_file.read();
-            active_file.id
+    // where to go on after the bad record at `offset` of `file`, `None` to stop reading it.
+    // only the tail of the active file may hold a torn write, it is truncated later
+    pub(crate) fn skip_bad_record(
+        &self,
+        file: &DataFile,
+        offset: u64,
+        active: bool,
+    ) -> Result<Option<u64>> {
+        match file.next_valid_record(offset + 1)? {
+            None if active => Ok(None),
+            next if self.opts.recovery_mode == RecoveryMode::SkipCorrupted => {
+                log::error!(
+                    "skip corrupted record of data file {} at offset {}",
+                    file.id,
+                    offset
+                );
+                Ok(next)
+            }
+            _ => Err(Error::Corrupted {
+                file_id: file.id,
+                offset,
+            }),
+        }
+    }
+
+    // apply a written record to the mem-index and account the space it makes reclaimable
+    pub(crate) fn update_index(&self, record: &Record, pos: RecordPosition) -> Result<()> {
+        let index = self.get_index(&record.key);
+
+        let snapshots = self.snapshots.read_recursive();
+        self.save_preimages(&snapshots, &record.key);
+
+        let expired = record.is_expired(now_millis());
+
+        let reclaimable = match record.record_type {
+            // an expired value deletes the key just like a tombstone
+            RecordType::Normal if expired => {
+                let pre_size = index.delete(&record.key).map_or(0, |pre_pos| pre_pos.size);
+
+                pre_size + pos.size
+            }
+            RecordType::Deleted => {
+                // a tombstone may target a key that is already gone
+                let pre_size = index.delete(&record.key).map_or(0, |pre_pos| pre_pos.size);
+
+                pre_size + pos.size
+            }
+            RecordType::Normal => index
+                .put(record.key.clone(), pos)?
+                .map_or(0, |pre_pos| pre_pos.size),
         };
 
-        if active_file_id == file_id {
-            let active_file = self.active_file.read();
+        self.reclaimable
+            .fetch_add(reclaimable as usize, Ordering::SeqCst);
 
-            active_file.read_record(offset)
-        } else {
-            let old_files = self.old_files.read();
+        Ok(())
+    }
 
-            old_files.get(&file_id).unwrap().read_record(offset)
+    fn get_record_with_offset(&self, file_id: u32, offset: u64) -> Result<RecordReader> {
+        self.with_data_file(file_id, |file| file.read_record(offset))
+    }
+
+    fn with_data_file<T>(&self, file_id: u32, f: impl FnOnce(&DataFile) -> Result<T>) -> Result<T> {
+        let active_file = self.active_file.read();
+        if active_file.id == file_id {
+            return f(&active_file);
         }
+        drop(active_file);
+
+        // a merge may have removed it
+        let old_files = self.old_files.read();
+        let file = old_files
+            .get(&file_id)
+            .ok_or(Error::DataFileNotFound(file_id))?;
+        f(file)
     }
 
     pub(crate) fn get_record_with_pos(&self, record_pos: RecordPosition) -> Result<RecordReader> {
-        let active_file_id = {
-            let active_file = self.active_file.read();
-            active_file.id
-        };
+        // hold the lock while reading, the active file may be sealed right after the check
+        let active_file = self.active_file.read();
+        if active_file.id == record_pos.file_id {
+            return active_file.read_record_with_size(record_pos.offset, record_pos.size as u64);
+        }
+        drop(active_file);
 
-        if active_file_id == record_pos.file_id {
-            let active_file = self.active_file.read();
-            active_file.read_record(record_pos.offset)
-        } else {
-            let old_files = self.old_files.read();
-            old_files
-                .get(&record_pos.file_id)
-                .unwrap()
-                .read_record(record_pos.offset)
+        let old_files = self.old_files.read();
+        old_files
+            .get(&record_pos.file_id)
+            .ok_or(Error::DataFileNotFound(record_pos.file_id))?
+            .read_record_with_size(record_pos.offset, record_pos.size as u64)
+    }
+
+    // read the record of `key` at `pos`. a merge may move the record after `pos`
+    // was looked up, in that case follow the mem-index to its new position
+    pub(crate) fn get_record_with_key(
+        &self,
+        key: &[u8],
+        mut pos: RecordPosition,
+    ) -> Result<RecordReader> {
+        loop {
+            let res = self.get_record_with_pos(pos);
+            if matches!(&res, Ok(record) if record.key() == key) {
+                return res;
+            }
+
+            match self.get_index(key).get(key) {
+                Some(new_pos) if new_pos != pos => pos = new_pos,
+                Some(_) => {
+                    res?;
+                    return Err(Error::Corrupted {
+                        file_id: pos.file_id,
+                        offset: pos.offset,
+                    });
+                }
+                None => return Err(Error::KeyNotFound),
+            }
         }
     }
 
     pub(crate) fn append_record(&self, record: &Record) -> Result<RecordPosition> {
+        if self.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let record_size = record.get_encode_len();
 
         let mut active_file = self.active_file.write();
         if active_file.write_offset as usize + record_size > self.opts.max_file_size {
-            // sync file
-            active_file.sync()?;
-
-            let prev_file_id = active_file.id;
-            let pre_active_file = std::mem::replace(
-                &mut *active_file,
-                DataFile::new(&self.opts.db_path, prev_file_id + 1)?,
-            );
-
-            self.old_files.write().insert(prev_file_id, pre_active_file);
+            self.rotate_active_file(&mut active_file, &mut self.old_files.write())?;
         }
 
         let write_offset = active_file.write_offset;
@@ -409,20 +838,68 @@ impl Bitcask {
 
         assert_eq!(write_offset + write_size as u64, active_file.write_offset);
 
-        self.bytes_written
-            .fetch_add(write_size as usize, Ordering::SeqCst);
+        let written = self
+            .bytes_written
+            .fetch_add(write_size as usize, Ordering::SeqCst)
+            + write_size as usize;
 
         // self.file_ids.push(active_file.id);
 
-        if self.opts.write_sync {
+        // `Always` is synced by the caller, once for a whole group or batch
+        if matches!(self.opts.sync_policy, SyncPolicy::EveryBytes(bytes) if written >= bytes) {
             active_file.sync()?;
+            self.bytes_written.store(0, Ordering::SeqCst);
         }
 
-        Ok(RecordPosition {
+        let pos = RecordPosition {
             file_id: active_file.id,
             offset: write_offset,
             size: write_size,
-        })
+        };
+        self.active_hints
+            .lock()
+            .extend(record.to_hint(pos).encode());
+
+        Ok(pos)
+    }
+
+    // seal the active file into `old_files` and start the next one.
+    // callers hold the write locks of both in this order
+    pub(crate) fn rotate_active_file(
+        &self,
+        active_file: &mut DataFile,
+        old_files: &mut HashMap<u32, DataFile>,
+    ) -> Result<()> {
+        active_file.sync()?;
+
+        let prev_file_id = active_file.id;
+        let mut pre_active_file = std::mem::replace(
+            active_file,
+            DataFile::new(&self.opts.db_path, prev_file_id + 1)?,
+        );
+        pre_active_file.set_io_type(&self.opts.db_path, self.opts.io_type)?;
+        old_files.insert(prev_file_id, pre_active_file);
+
+        // the data file is complete without its hints, they only speed up the next open
+        let hints = std::mem::take(&mut *self.active_hints.lock());
+        if let Err(e) = self.write_data_hint_file(prev_file_id, &hints) {
+            log::error!("write hint file of data file {} error: {}", prev_file_id, e);
+        }
+
+        Ok(())
+    }
+
+    // write to a temporary file first, a hint file is either complete or missing
+    fn write_data_hint_file(&self, file_id: u32, hints: &[u8]) -> Result<()> {
+        let path = get_data_hint_file_path(&self.opts.db_path, file_id);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(hints)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
     }
 }
 
@@ -440,23 +917,48 @@ impl Bitcask {
     }
 
     pub(crate) fn txn_write(&self, record: Record) -> Result<()> {
-        let pos = self.append_record(&record)?;
+        let _shard = self.lock_shard(&record.key);
+        self.txn_append(record)
+    }
 
-        self.get_index(&record.key)
-            .put(record.key, pos)
-            .map_err(|_| anyhow::Error::msg("txn write: update index error!"))?;
+    // versions are internal to transactions, subscribers never see them go
+    pub(crate) fn txn_delete(&self, record: Record) -> Result<()> {
+        let _shard = self.lock_shard(&record.key);
+        if !self.get_index(&record.key).exits(&record.key) {
+            return Ok(());
+        }
 
-        Ok(())
+        self.txn_append(record)
+    }
+
+    // the caller holds the shard lock of the key
+    fn txn_append(&self, record: Record) -> Result<()> {
+        let (record, pos) = self.commit_record(record)?;
+
+        self.update_index(&record, pos)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::OpenOptions, path::Path};
-
-    use anyhow::Result;
-
-    use crate::{options::BitcaskOptions, storage::Bitcask};
+    use std::{
+        fs::{OpenOptions, Permissions},
+        io::Write,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::Duration,
+    };
+
+    use crate::{
+        consts::{BPTREE_INDEX_FILE_NAME, HINT_FILE_NAME, MERGE_FILE_NAME},
+        data::log_record::Record,
+        error::{Error, Result},
+        key::MAX_KEY_SIZE,
+        options::{BitcaskOptions, IndexType, IoType, RecoveryMode, WriteBatchOptions},
+        storage::Bitcask,
+        transaction::TxnEngine,
+        utils::{get_data_file_path, get_data_hint_file_path, get_merge_path},
+    };
 
     fn clear_directory(path: impl AsRef<Path>) -> Result<()> {
         for entry in std::fs::read_dir(path)? {
@@ -478,8 +980,13 @@ mod tests {
 
     #[test]
     fn test_bitcask_put_get_delete() -> Result<()> {
-        let ops = BitcaskOptions::default();
-        let bitcask = Bitcask::open(ops)?;
+        let path = "/tmp/bitcask_put_get_delete";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
+        let bitcask = Bitcask::open(ops.clone())?;
 
         bitcask.put("foo", "ddd").unwrap();
         bitcask.put("ddd", "foo").unwrap();
@@ -493,19 +1000,28 @@ mod tests {
         // bitcask.put("", "foo").unwrap();
 
         bitcask.delete("foo").unwrap();
-        println!("{}", bitcask.get_size());
 
         // bitcask.get("foo").unwrap();
+        assert!(matches!(bitcask.get("foo"), Err(Error::KeyNotFound)));
+        assert!(matches!(bitcask.put("", "foo"), Err(Error::KeyEmpty)));
+
+        // the lock file is held until the engine is closed
+        assert!(matches!(Bitcask::open(ops), Err(Error::DatabaseLocked)));
 
-        clear_directory("/tmp/bitcask_tmp")?;
+        drop(bitcask);
+        clear_directory(path)?;
         Ok(())
     }
 
     #[test]
     fn test_bitcask_merge() -> Result<()> {
-        let ops = BitcaskOptions::default();
+        let path = "/tmp/bitcask_merge";
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
 
-        let bitcask = Bitcask::open(ops)?;
+        let bitcask = Bitcask::open(ops.clone())?;
 
         for i in 0..1000 {
             for _ in 0..100 {
@@ -518,7 +1034,6 @@ mod tests {
         bitcask.merge()?;
         bitcask.close()?;
 
-        let ops = BitcaskOptions::default();
         let bitcask = Bitcask::open(ops)?;
         for i in 0..1000 {
             let value = bitcask.get(format!("{:09}", i))?;
@@ -528,12 +1043,779 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_bitcask_merge_interrupted() -> Result<()> {
+        let path = "/tmp/bitcask_merge_interrupted";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), "old")?;
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+
+            // the merge hint file cannot be moved into place
+            let blocker = Path::new(path).join(HINT_FILE_NAME);
+            std::fs::create_dir_all(blocker.join("blocker"))?;
+            assert!(bitcask.merge().is_err());
+            for i in 0..300 {
+                assert_eq!(
+                    format!("{:04}", i).into_bytes(),
+                    bitcask.get(format!("{:04}", i))?
+                );
+            }
+            std::fs::remove_dir_all(blocker)?;
+            bitcask.close()?;
+        }
+
+        // the next open finishes moving the merged files
+        let bitcask = Bitcask::open(ops)?;
+        for i in 0..300 {
+            assert_eq!(
+                format!("{:04}", i).into_bytes(),
+                bitcask.get(format!("{:04}", i))?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_stat() -> Result<()> {
+        let path = "/tmp/bitcask_stat";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let record_size = Record::normal("000".into(), "000".into()).get_encode_len();
+        let tombstone_size = Record::deleted("000".into()).get_encode_len();
+        let expected = 50 * record_size + 10 * (record_size + tombstone_size);
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+
+            for i in 0..100 {
+                bitcask.put(format!("{:03}", i), format!("{:03}", i))?;
+            }
+            for i in 0..50 {
+                bitcask.put(format!("{:03}", i), format!("{:03}", i + 1))?;
+            }
+            for i in 0..10 {
+                bitcask.delete(format!("{:03}", i))?;
+            }
+
+            let stat = bitcask.stat()?;
+            assert_eq!(90, stat.key_num);
+            assert_eq!(expected, stat.reclaimable_size);
+            assert!(stat.data_file_num > 1);
+            assert!(stat.disk_used >= 150 * record_size + 10 * tombstone_size);
+            bitcask.close()?;
+        }
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            let stat = bitcask.stat()?;
+            assert_eq!(90, stat.key_num);
+            assert_eq!(expected, stat.reclaimable_size);
+
+            bitcask.merge()?;
+            let merged = bitcask.stat()?;
+            assert_eq!(90, merged.key_num);
+            assert_eq!(0, merged.reclaimable_size);
+            bitcask.close()?;
+        }
+
+        let bitcask = Bitcask::open(ops)?;
+        let stat = bitcask.stat()?;
+        assert_eq!(90, stat.key_num);
+        assert_eq!(0, stat.reclaimable_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_online_merge() -> Result<()> {
+        let path = "/tmp/bitcask_online_merge";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 16 << 10,
+            ..Default::default()
+        };
+
+        let bitcask = Bitcask::open(ops.clone())?;
+        for round in 0..20 {
+            for i in 0..200 {
+                bitcask.put(format!("{:04}", i), format!("{:04}-{}", i, round))?;
+            }
+        }
+        let before = bitcask.stat()?;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let bitcask = bitcask.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        for i in 0..200 {
+                            let value = bitcask.get(format!("{:04}", i)).unwrap();
+                            assert_eq!(format!("{:04}-19", i).as_bytes(), value.as_slice());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        bitcask.merge()?;
+        readers
+            .into_iter()
+            .for_each(|reader| reader.join().unwrap());
+
+        let after = bitcask.stat()?;
+        assert!(after.data_file_num < before.data_file_num);
+        assert!(after.disk_used < before.disk_used);
+        assert_eq!(0, after.reclaimable_size);
+        assert!(!get_merge_path(path).exists());
+
+        // writes keep going to the new active file
+        bitcask.put("0000", "new")?;
+        bitcask.delete("0001")?;
+        bitcask.close()?;
+        drop(bitcask);
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"new".to_vec(), bitcask.get("0000")?);
+        assert!(bitcask.get("0001").is_err());
+        for i in 2..200 {
+            let value = bitcask.get(format!("{:04}", i))?;
+            assert_eq!(format!("{:04}-19", i).as_bytes(), value.as_slice());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_merge_concurrent_writes() -> Result<()> {
+        let path = "/tmp/bitcask_merge_concurrent_writes";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let bitcask = Bitcask::open(ops.clone())?;
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let bitcask = bitcask.clone();
+                std::thread::spawn(move || {
+                    for round in 0..300 {
+                        for i in 0..10 {
+                            let key = format!("{}-{}", writer, i);
+                            bitcask.put(key, format!("{}", round)).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // no write made during a merge is lost
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            bitcask.merge()?;
+        }
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        bitcask.merge()?;
+
+        let check = |bitcask: &Bitcask| -> Result<()> {
+            for writer in 0..4 {
+                for i in 0..10 {
+                    let value = bitcask.get(format!("{}-{}", writer, i))?;
+                    assert_eq!(b"299".to_vec(), value);
+                }
+            }
+            Ok(())
+        };
+        check(&bitcask)?;
+        bitcask.close()?;
+        drop(bitcask);
+
+        check(&*Bitcask::open(ops)?)
+    }
+
+    #[test]
+    fn test_bitcask_mmap() -> Result<()> {
+        let path = "/tmp/bitcask_mmap";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            io_type: IoType::MemoryMap,
+            ..Default::default()
+        };
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..500 {
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+            assert!(bitcask.stat()?.data_file_num > 1);
+
+            for i in 0..500 {
+                let value = bitcask.get(format!("{:04}", i))?;
+                assert_eq!(format!("{:04}", i).as_bytes(), value.as_slice());
+            }
+            bitcask.close()?;
+        }
+
+        let bitcask = Bitcask::open(ops)?;
+        bitcask.put("new", "value")?;
+        assert_eq!(b"value".to_vec(), bitcask.get("new")?);
+        for i in 0..500 {
+            let value = bitcask.get(format!("{:04}", i))?;
+            assert_eq!(format!("{:04}", i).as_bytes(), value.as_slice());
+        }
+
+        bitcask.merge()?;
+        assert_eq!(b"0001".to_vec(), bitcask.get("0001")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_ttl() -> Result<()> {
+        let path = "/tmp/bitcask_ttl";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            bitcask.put("forever", "v")?;
+            bitcask.put("short", "v")?;
+            bitcask.put_with_ttl("short", "v", Duration::from_millis(50))?;
+            bitcask.put_with_ttl("long", "v", Duration::from_secs(3600))?;
+            bitcask.put_with_ttl("huge", "v", Duration::MAX)?;
+
+            assert_eq!(b"v".to_vec(), bitcask.get("short")?);
+            assert_eq!(b"v".to_vec(), bitcask.get("huge")?);
+            assert!(bitcask.ttl("huge")?.unwrap() > Duration::from_secs(3600 * 24 * 365));
+            assert!(bitcask.ttl("forever")?.is_none());
+            assert!(bitcask.ttl("long")?.unwrap() > Duration::from_secs(3500));
+
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(bitcask.get("short").is_err());
+            assert!(bitcask.ttl("short").is_err());
+            assert_eq!(b"v".to_vec(), bitcask.get("long")?);
+
+            // still listed until a merge or the next open
+            assert_eq!(4, bitcask.stat()?.key_num);
+            let short = bitcask.scan_prefix("short")?.next().unwrap();
+            assert!(matches!(short.value(), Err(Error::KeyNotFound)));
+            bitcask.close()?;
+        }
+
+        {
+            // expired keys are skipped when loading the index
+            let bitcask = Bitcask::open(ops.clone())?;
+            assert_eq!(3, bitcask.stat()?.key_num);
+            assert!(bitcask.get("short").is_err());
+
+            bitcask.put_with_ttl("merged", "v", Duration::from_millis(50))?;
+            std::thread::sleep(Duration::from_millis(100));
+
+            // and dropped by merge
+            bitcask.put_with_ttl("later", "v", Duration::from_millis(300))?;
+            bitcask.merge()?;
+            assert_eq!(4, bitcask.stat()?.key_num);
+            assert_eq!(0, bitcask.stat()?.reclaimable_size);
+            bitcask.close()?;
+        }
+
+        // expired in the merged files, counted as reclaimable like in the data files
+        std::thread::sleep(Duration::from_millis(300));
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(3, bitcask.stat()?.key_num);
+        assert!(bitcask.get("merged").is_err());
+        assert!(bitcask.ttl("long")?.is_some());
+        let later_size = Record::normal_with_expire("later".into(), "v".into(), 0).get_encode_len();
+        assert_eq!(later_size, bitcask.stat()?.reclaimable_size);
+
+        bitcask.merge()?;
+        assert_eq!(0, bitcask.stat()?.reclaimable_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_auto_merge() -> Result<()> {
+        let path = "/tmp/bitcask_auto_merge";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            auto_merge: true,
+            merge_ratio: 0.5,
+            merge_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+
+        let bitcask = Bitcask::open(ops)?;
+        for _ in 0..10 {
+            for i in 0..100 {
+                bitcask.put(format!("{:03}", i), format!("{:03}", i))?;
+            }
+        }
+
+        let merge_file = Path::new(path).join(MERGE_FILE_NAME);
+        let mut waited = 0;
+        while !merge_file.is_file() && waited < 100 {
+            std::thread::sleep(Duration::from_millis(20));
+            waited += 1;
+        }
+        assert!(merge_file.is_file());
+
+        bitcask.close()?;
+        assert!(bitcask.merge_worker.lock().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_index_types() -> Result<()> {
+        for (i, index_type) in [
+            IndexType::BTree,
+            IndexType::HashMap,
+            IndexType::Art,
+            IndexType::BPlusTree,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let path = format!("/tmp/bitcask_index_type_{}", i);
+            let _ = std::fs::remove_dir_all(&path);
+            let ops = BitcaskOptions {
+                db_path: path.into(),
+                max_file_size: 4 << 10,
+                index_type,
+                ..Default::default()
+            };
+
+            {
+                let bitcask = Bitcask::open(ops.clone())?;
+                for i in 0..300 {
+                    bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+                }
+                for i in 0..100 {
+                    bitcask.delete(format!("{:04}", i))?;
+                }
+                bitcask.merge()?;
+                bitcask.close()?;
+            }
+
+            let bitcask = Bitcask::open(ops)?;
+            assert_eq!(200, bitcask.stat()?.key_num);
+            assert!(bitcask.get("0050").is_err());
+            assert_eq!(b"0150".to_vec(), bitcask.get("0150")?);
+
+            match index_type {
+                IndexType::HashMap => assert!(bitcask.scan_prefix("01").is_err()),
+                _ => assert_eq!(100, bitcask.scan_prefix("01")?.count()),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_bptree_reopen() -> Result<()> {
+        let path = "/tmp/bitcask_bptree_index";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            index_type: IndexType::BPlusTree,
+            ..Default::default()
+        };
+
+        let before = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+            for i in 0..100 {
+                bitcask.delete(format!("{:04}", i))?;
+            }
+            let stat = bitcask.stat()?;
+            bitcask.close()?;
+            stat
+        };
+        assert!(Path::new(path).join(BPTREE_INDEX_FILE_NAME).is_file());
+
+        // the clean index is used as is, writes go on after the last record
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            let stat = bitcask.stat()?;
+            assert_eq!(before.key_num, stat.key_num);
+            assert_eq!(before.reclaimable_size, stat.reclaimable_size);
+
+            bitcask.put("0000", "new")?;
+            assert_eq!(b"0299".to_vec(), bitcask.get("0299")?);
+            // dropped without close, the next open rebuilds the index
+        }
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            assert_eq!(before.key_num + 1, bitcask.stat()?.key_num);
+            assert_eq!(b"new".to_vec(), bitcask.get("0000")?);
+            assert!(bitcask.get("0001").is_err());
+            assert_eq!(b"0150".to_vec(), bitcask.get("0150")?);
+            bitcask.close()?;
+        }
+
+        // writes through another index type are not lost on the next b+tree open
+        {
+            let bitcask = Bitcask::open(BitcaskOptions {
+                index_type: IndexType::SkipList,
+                ..ops.clone()
+            })?;
+            bitcask.put("0001", "other")?;
+            bitcask.close()?;
+        }
+        assert!(!Path::new(path).join(BPTREE_INDEX_FILE_NAME).exists());
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"other".to_vec(), bitcask.get("0001")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_key_too_large() -> Result<()> {
+        let path = "/tmp/bitcask_key_too_large";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            index_type: IndexType::BPlusTree,
+            ..Default::default()
+        };
+
+        // the longest key leaves room for the ts of a transaction
+        let longest = vec![b'k'; MAX_KEY_SIZE - 8];
+        let too_large = vec![b'k'; MAX_KEY_SIZE - 7];
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            assert!(matches!(
+                bitcask.put(&too_large, "x"),
+                Err(Error::KeyTooLarge { .. })
+            ));
+            let batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+            assert!(batch.put(&too_large, "x").is_err());
+
+            bitcask.put(&longest, "bar")?;
+            // dropped without close, the next open rebuilds the index from the data files
+        }
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"bar".to_vec(), bitcask.get(&longest)?);
+        // reads never check the size
+        assert!(matches!(bitcask.get(&too_large), Err(Error::KeyNotFound)));
+
+        let txn_engine = TxnEngine::new(bitcask)?;
+        let txn = txn_engine.begin_transaction();
+        assert!(txn.put(&too_large, "x").is_err());
+        txn.put(&longest, "txn")?;
+        txn.commit()?;
+
+        // the other indexes take keys of any size
+        let path = "/tmp/bitcask_key_too_large_skiplist";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
+        let huge = vec![b'k'; MAX_KEY_SIZE * 4];
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            bitcask.put(&huge, "x")?;
+            bitcask.close()?;
+        }
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"x".to_vec(), bitcask.get(&huge)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_data_hint_files() -> Result<()> {
+        let path = "/tmp/bitcask_data_hint";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let before = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..200 {
+                bitcask.put_with_ttl(format!("{:04}", i), "x", Duration::from_secs(600))?;
+            }
+            for i in 0..50 {
+                bitcask.delete(format!("{:04}", i))?;
+            }
+
+            // a batch crossing a file rotation
+            let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+            for i in 0..100 {
+                batch.put(format!("b{:04}", i), format!("{:064}", i))?;
+            }
+            batch.commit()?;
+            for i in 0..50 {
+                bitcask.put(format!("{:04}", i), "y")?;
+            }
+
+            let stat = bitcask.stat()?;
+            bitcask.close()?;
+            stat
+        };
+
+        // every sealed file has its hints, the active one has none
+        let ids = Bitcask::load_data_file_ids(path)?;
+        let (active_id, sealed_ids) = ids.split_last().unwrap();
+        for id in sealed_ids {
+            assert!(get_data_hint_file_path(path, *id).is_file());
+        }
+        assert!(!get_data_hint_file_path(path, *active_id).exists());
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            let stat = bitcask.stat()?;
+            assert_eq!(before.key_num, stat.key_num);
+            assert_eq!(before.reclaimable_size, stat.reclaimable_size);
+            assert_eq!(b"y".to_vec(), bitcask.get("0010")?);
+            assert_eq!(format!("{:064}", 99).into_bytes(), bitcask.get("b0099")?);
+            assert!(bitcask.ttl("0100")?.is_some());
+
+            // the active file keeps its hints across the reopen
+            for i in 0..100 {
+                bitcask.put(format!("c{:04}", i), format!("{:064}", i))?;
+            }
+            bitcask.close()?;
+        }
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(before.key_num + 100, bitcask.stat()?.key_num);
+        assert_eq!(format!("{:064}", 0).into_bytes(), bitcask.get("c0000")?);
+
+        // merged files drop their hints
+        bitcask.merge()?;
+        for id in sealed_ids {
+            assert!(!get_data_hint_file_path(path, *id).exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_torn_tail() -> Result<()> {
+        let path = "/tmp/bitcask_torn_tail";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            ..Default::default()
+        };
+
+        let active_id = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..100 {
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+            let active_id = bitcask.active_file.read().id;
+            bitcask.close()?;
+            active_id
+        };
+
+        // half of a record is left behind by a crash
+        let data_path = get_data_file_path(path, active_id);
+        let len = std::fs::metadata(&data_path)?.len();
+        let torn = Record::normal("torn".into(), "value".into()).encode();
+        let mut file = OpenOptions::new().append(true).open(&data_path)?;
+        file.write_all(&torn[..torn.len() / 2])?;
+        drop(file);
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            assert_eq!(len, std::fs::metadata(&data_path)?.len());
+            assert!(matches!(bitcask.get("torn"), Err(Error::KeyNotFound)));
+
+            bitcask.put("after", "torn")?;
+            bitcask.close()?;
+        }
+
+        let bitcask = Bitcask::open(ops)?;
+        assert_eq!(b"0099".to_vec(), bitcask.get("0099")?);
+        assert_eq!(b"torn".to_vec(), bitcask.get("after")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_corrupted_record() -> Result<()> {
+        let path = "/tmp/bitcask_corrupted_record";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let pos = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:032}", i))?;
+            }
+            let pos = bitcask.get_index(b"0010").get(b"0010").unwrap();
+            bitcask.close()?;
+            pos
+        };
+
+        // flip a byte in the value of a record in the middle of a sealed file,
+        // without its hints the data file itself is read
+        std::fs::remove_file(get_data_hint_file_path(path, pos.file_id))?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(get_data_file_path(path, pos.file_id))?;
+        let offset = pos.offset + pos.size as u64 - 5;
+        std::os::unix::fs::FileExt::write_at(&file, &[0xff], offset)?;
+
+        assert!(matches!(
+            Bitcask::open(ops.clone()),
+            Err(Error::Corrupted { file_id, offset }) if file_id == pos.file_id && offset == pos.offset
+        ));
+
+        let bitcask = Bitcask::open(BitcaskOptions {
+            recovery_mode: RecoveryMode::SkipCorrupted,
+            ..ops
+        })?;
+        assert!(matches!(bitcask.get("0010"), Err(Error::KeyNotFound)));
+        assert_eq!(format!("{:032}", 11).into_bytes(), bitcask.get("0011")?);
+        assert_eq!(299, bitcask.stat()?.key_num);
+
+        // merge skips the bad record as well
+        bitcask.merge()?;
+        assert_eq!(format!("{:032}", 299).into_bytes(), bitcask.get("0299")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitcask_read_only() -> Result<()> {
+        let path = "/tmp/bitcask_read_only";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+            // leaves the hint file and mark of the merge behind, later files get their own hints
+            bitcask.merge()?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:04}", i))?;
+            }
+            bitcask.close()?;
+        }
+        let files = || -> Result<Vec<_>> {
+            let mut files = std::fs::read_dir(path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((entry.file_name(), entry.metadata()?.len()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let before = files()?;
+
+        // nothing under the path may be opened for writing
+        let set_mode = |file_mode, dir_mode| -> Result<()> {
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                std::fs::set_permissions(path, Permissions::from_mode(file_mode))?;
+            }
+            std::fs::set_permissions(path, Permissions::from_mode(dir_mode))?;
+            Ok(())
+        };
+        set_mode(0o444, 0o555)?;
+
+        let read_only = BitcaskOptions {
+            read_only: true,
+            ..ops.clone()
+        };
+        let readers = [
+            Bitcask::open(read_only.clone())?,
+            Bitcask::open(read_only.clone())?,
+        ];
+
+        for reader in readers.iter() {
+            assert_eq!(300, reader.stat()?.key_num);
+            assert_eq!(b"0150".to_vec(), reader.get("0150")?);
+            assert!(matches!(reader.put("foo", "bar"), Err(Error::ReadOnly)));
+            assert!(matches!(reader.delete("0150"), Err(Error::ReadOnly)));
+            assert!(matches!(reader.merge(), Err(Error::ReadOnly)));
+
+            let mut batch = reader.new_batch_write(WriteBatchOptions::default())?;
+            batch.put("foo", "bar")?;
+            assert!(matches!(batch.commit(), Err(Error::ReadOnly)));
+        }
+
+        // a writer needs the lock file writable to find it locked
+        set_mode(0o644, 0o755)?;
+        assert!(matches!(
+            Bitcask::open(ops.clone()),
+            Err(Error::DatabaseLocked)
+        ));
+        for reader in readers.iter() {
+            reader.close()?;
+        }
+        drop(readers);
+        assert_eq!(before, files()?);
+
+        // nothing is created for a missing database
+        assert!(Bitcask::open(BitcaskOptions {
+            db_path: "/tmp/bitcask_read_only_missing".into(),
+            ..read_only
+        })
+        .is_err());
+        assert!(!Path::new("/tmp/bitcask_read_only_missing").exists());
+
+        let bitcask = Bitcask::open(ops)?;
+        bitcask.put("foo", "bar")?;
+
+        Ok(())
+    }
+
     #[test]
     fn t() {
+        std::fs::create_dir_all("/tmp/bitcask_tmp").unwrap();
         OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
             .open("/tmp/bitcask_tmp/index.HINT")
             .unwrap();
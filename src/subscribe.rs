@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::{
+    data::log_record::{BatchState, Record, RecordPosition, RecordType},
+    error::Result,
+    key::TXN_TS_SIZE,
+    storage::Bitcask,
+};
+
+// a committed put or delete
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+}
+
+// position in the data files right after a commit, `subscribe_from` goes on from it.
+// a merge rewrites the files it covers, positions in them are stale after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChangePosition {
+    pub file_id: u32,
+    pub offset: u64,
+}
+
+// changes of one commit, a batch or a transaction arrives as a whole
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub changes: Vec<Change>,
+    // set for a transaction commit
+    pub txn_ts: Option<u64>,
+    pub position: Option<ChangePosition>,
+}
+
+// records of batches and transactions read before their end
+#[derive(Default)]
+struct Pending {
+    batches: HashMap<u64, Vec<(Record, RecordPosition)>>,
+    txns: HashMap<u64, Vec<(Record, RecordPosition)>>,
+}
+
+pub(crate) struct Subscriber {
+    prefix: Vec<u8>,
+    sender: Sender<ChangeEvent>,
+    // events up to here were replayed from the data files
+    replayed: Option<ChangePosition>,
+}
+
+impl Change {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Change::Put { key, .. } | Change::Delete { key } => key,
+        }
+    }
+
+    pub(crate) fn from_record(record: &Record) -> Self {
+        match record.record_type {
+            RecordType::Normal => Change::Put {
+                key: record.key.clone(),
+                value: record.value.clone(),
+                expire_at: record.expire_at,
+            },
+            RecordType::Deleted => Change::Delete {
+                key: record.key.clone(),
+            },
+        }
+    }
+
+    // a transaction writes the key followed by its ts
+    fn from_txn_record(record: &Record) -> Self {
+        let key = record.key[..record.key.len().saturating_sub(TXN_TS_SIZE)].to_vec();
+
+        match Change::from_record(record) {
+            Change::Put {
+                value, expire_at, ..
+            } => Change::Put {
+                key,
+                value,
+                expire_at,
+            },
+            Change::Delete { .. } => Change::Delete { key },
+        }
+    }
+}
+
+impl ChangePosition {
+    pub(crate) fn after(pos: RecordPosition) -> Self {
+        Self {
+            file_id: pos.file_id,
+            offset: pos.offset + pos.size as u64,
+        }
+    }
+}
+
+impl ChangeEvent {
+    // event of a single record written at `pos`
+    pub(crate) fn write(record: &Record, pos: RecordPosition) -> Self {
+        Self {
+            changes: vec![Change::from_record(record)],
+            txn_ts: None,
+            position: Some(ChangePosition::after(pos)),
+        }
+    }
+
+    pub(crate) fn batch(records: &[(Record, RecordPosition)], finish_pos: RecordPosition) -> Self {
+        Self {
+            changes: records
+                .iter()
+                .map(|(record, _)| Change::from_record(record))
+                .collect(),
+            txn_ts: None,
+            position: Some(ChangePosition::after(finish_pos)),
+        }
+    }
+
+    // the last version the transaction wrote of each key
+    fn txn(ts: u64, records: &[(Record, RecordPosition)], commit_pos: RecordPosition) -> Self {
+        let mut changes: Vec<Change> = Vec::new();
+        let mut seen = HashMap::new();
+        for (record, _) in records {
+            let change = Change::from_txn_record(record);
+            match seen.get(change.key()) {
+                Some(&i) => changes[i] = change,
+                None => {
+                    seen.insert(change.key().to_vec(), changes.len());
+                    changes.push(change);
+                }
+            }
+        }
+
+        Self {
+            changes,
+            txn_ts: Some(ts),
+            position: Some(ChangePosition::after(commit_pos)),
+        }
+    }
+}
+
+impl Subscriber {
+    // the changes under the prefix, false once the receiver is gone
+    fn send(&self, event: &ChangeEvent) -> bool {
+        if event.position.is_some() && event.position <= self.replayed {
+            return true;
+        }
+
+        let changes: Vec<Change> = event
+            .changes
+            .iter()
+            .filter(|change| change.key().starts_with(&self.prefix))
+            .cloned()
+            .collect();
+        if changes.is_empty() {
+            return true;
+        }
+
+        self.sender
+            .send(ChangeEvent {
+                changes,
+                ..event.clone()
+            })
+            .is_ok()
+    }
+}
+
+impl Bitcask {
+    // every change committed from now on to a key starting with `prefix`
+    pub fn subscribe(&self, prefix: impl AsRef<[u8]>) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = unbounded();
+
+        self.subscribers.lock().push(Subscriber {
+            prefix: prefix.as_ref().to_vec(),
+            sender,
+            replayed: None,
+        });
+
+        receiver
+    }
+
+    // like `subscribe`, after the changes still in the data files from `position` on
+    pub fn subscribe_from(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        position: ChangePosition,
+    ) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = unbounded();
+        let mut subscriber = Subscriber {
+            prefix: prefix.as_ref().to_vec(),
+            sender,
+            replayed: None,
+        };
+        let mut pending = Pending::default();
+
+        // no merge removes the files in between
+        let _merge_guard = self.merge_lock.lock();
+
+        // writers only wait for the tail written while the rest is replayed
+        let position = self.replay(&subscriber, position, &mut pending)?;
+        let mut subscribers = self.subscribers.lock();
+        subscriber.replayed = Some(self.replay(&subscriber, position, &mut pending)?);
+        subscribers.push(subscriber);
+
+        Ok(receiver)
+    }
+
+    // send the changes of the data files from `position` on, return the position after them
+    fn replay(
+        &self,
+        subscriber: &Subscriber,
+        mut position: ChangePosition,
+        pending: &mut Pending,
+    ) -> Result<ChangePosition> {
+        // the active file first, a rotation right after leaves it among the old files
+        let active_file_id = self.active_file.read().id;
+        let mut file_ids: Vec<u32> = self.old_files.read().keys().copied().collect();
+        file_ids.push(active_file_id);
+        file_ids.sort();
+        file_ids.dedup();
+
+        for file_id in file_ids {
+            if file_id < position.file_id {
+                continue;
+            }
+            let offset = match file_id == position.file_id {
+                true => position.offset,
+                false => 0,
+            };
+
+            let end = self.read_records(file_id, offset, |record, pos| {
+                let event = match record.batch_state {
+                    BatchState::Enable(seq) => {
+                        pending.batches.entry(seq).or_default().push((record, pos));
+                        return Ok(());
+                    }
+                    BatchState::Finish(seq) => {
+                        ChangeEvent::batch(&pending.batches.remove(&seq).unwrap_or_default(), pos)
+                    }
+                    BatchState::Disable => ChangeEvent::write(&record, pos),
+                    // no commit mark follows the versions a rollback or clean up removes
+                    BatchState::Txn(ts) => {
+                        pending.txns.entry(ts).or_default().push((record, pos));
+                        return Ok(());
+                    }
+                    BatchState::TxnCommit(ts) => {
+                        ChangeEvent::txn(ts, &pending.txns.remove(&ts).unwrap_or_default(), pos)
+                    }
+                    // an old version a merge copied, its change was sent before
+                    BatchState::Snapshot => return Ok(()),
+                };
+                subscriber.send(&event);
+
+                Ok(())
+            })?;
+            position = ChangePosition {
+                file_id,
+                offset: end,
+            };
+        }
+
+        Ok(position)
+    }
+
+    // apply a commit to the index and send its changes to the subscribers. callers hold
+    // the shard locks of the keys until it returns, so the events of a key arrive in the
+    // order of the index while the subscribers lock is only held to send them.
+    // a commit missed by a `subscribe_from` still registering is in the data files it replays
+    pub(crate) fn publish<T>(
+        &self,
+        apply: impl FnOnce() -> Result<T>,
+        event: impl FnOnce(&T) -> Option<ChangeEvent>,
+    ) -> Result<T> {
+        let res = apply()?;
+        if self.subscribers.lock().is_empty() {
+            return Ok(res);
+        }
+
+        if let Some(event) = event(&res) {
+            self.subscribers
+                .lock()
+                .retain(|subscriber| subscriber.send(&event));
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        options::{BitcaskOptions, WriteBatchOptions},
+        transaction::TxnEngine,
+        utils::TestDir,
+    };
+
+    use super::*;
+
+    fn put(key: &str, value: &str) -> Change {
+        Change::Put {
+            key: key.into(),
+            value: value.into(),
+            expire_at: None,
+        }
+    }
+
+    #[test]
+    fn test_subscribe() -> Result<()> {
+        let dir = TestDir::new("bitcask_subscribe");
+        let bitcask = Bitcask::open(BitcaskOptions {
+            max_file_size: 4 << 10,
+            ..dir.options()
+        })?;
+
+        for i in 0..200 {
+            bitcask.put(format!("old:{:04}", i), "x")?;
+        }
+
+        let all = bitcask.subscribe("");
+        let users = bitcask.subscribe("user:");
+
+        bitcask.put("user:1", "a")?;
+        bitcask.put("post:1", "b")?;
+        bitcask.delete("user:1")?;
+        bitcask.delete("missing")?;
+        let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+        batch.put("user:2", "c")?;
+        batch.put("post:2", "d")?;
+        batch.commit()?;
+
+        let events: Vec<ChangeEvent> = users.try_iter().collect();
+        assert_eq!(3, events.len());
+        assert_eq!(vec![put("user:1", "a")], events[0].changes);
+        assert_eq!(
+            vec![Change::Delete {
+                key: "user:1".into()
+            }],
+            events[1].changes
+        );
+        assert_eq!(vec![put("user:2", "c")], events[2].changes);
+
+        let events: Vec<ChangeEvent> = all.try_iter().collect();
+        assert_eq!(4, events.len());
+        assert_eq!(2, events[3].changes.len());
+        assert!(events[3].changes.contains(&put("post:2", "d")));
+
+        // resume after the first event, the rest is replayed once and then follows live
+        let resumed = bitcask.subscribe_from("", events[0].position.unwrap())?;
+        bitcask.put("post:3", "e")?;
+        let replayed: Vec<ChangeEvent> = resumed.try_iter().collect();
+        assert_eq!(&events[1..], &replayed[..3]);
+        assert_eq!(vec![put("post:3", "e")], replayed[3].changes);
+        assert_eq!(4, replayed.len());
+
+        let from_start = bitcask.subscribe_from(
+            "old:",
+            ChangePosition {
+                file_id: 0,
+                offset: 0,
+            },
+        )?;
+        assert_eq!(200, from_start.try_iter().count());
+
+        // a transaction arrives with its ts, under the keys it wrote
+        drop(all);
+        drop(resumed);
+        let txn_engine = TxnEngine::new(bitcask.clone())?;
+        let txn = txn_engine.begin_transaction();
+        txn.put("user:3", "f")?;
+        txn.put("user:3", "g")?;
+        txn.delete("user:4")?;
+        txn.commit()?;
+
+        let events: Vec<ChangeEvent> = users.try_iter().collect();
+        assert_eq!(1, events.len());
+        assert!(events[0].txn_ts.is_some());
+        assert!(events[0].position.is_some());
+        assert_eq!(
+            vec![
+                put("user:3", "g"),
+                Change::Delete {
+                    key: "user:4".into()
+                }
+            ],
+            events[0].changes
+        );
+        assert_eq!(2, bitcask.subscribers.lock().len());
+
+        // a replay sends the committed transaction as it went out live, without the rollback
+        let txn = txn_engine.begin_transaction();
+        txn.put("user:5", "h")?;
+        txn.rollback()?;
+        let from_txn = bitcask.subscribe_from("user:", replayed[3].position.unwrap())?;
+        assert_eq!(events, from_txn.try_iter().collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_concurrent() -> Result<()> {
+        let dir = TestDir::new("bitcask_subscribe_concurrent");
+        let bitcask = Bitcask::open(dir.options())?;
+        let all = bitcask.subscribe("");
+
+        // writers of all shards at once, the last event of a key is what it holds
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let bitcask = bitcask.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for i in 0..500 {
+                        bitcask.put(format!("{}", i % 20), format!("{}-{}", t, i))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+
+        let mut last = HashMap::new();
+        for event in all.try_iter() {
+            for change in event.changes {
+                if let Change::Put { key, value, .. } = change {
+                    last.insert(key, value);
+                }
+            }
+        }
+        assert_eq!(20, last.len());
+        for (key, value) in last {
+            assert_eq!(value, bitcask.get(key)?);
+        }
+
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
 pub(crate) mod engine;
 pub(crate) mod manager;
 
-use std::{collections::HashSet, sync::Arc};
+pub use engine::TxnEngine;
+
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc},
+};
 
-use anyhow::Result;
 use manager::TxnManager;
 
 use crate::{
-    data::log_record::Record,
-    key::{check_key_valid, Key},
+    data::log_record::{BatchState, Record, RecordPosition},
+    error::{Error, Result},
+    key::{check_key_size, check_key_valid, Key},
     storage::Bitcask,
+    subscribe::{Change, ChangeEvent, ChangePosition},
 };
 
 pub(crate) struct KeySlice(Key, u64);
@@ -23,6 +29,15 @@ impl KeySlice {
         self.0.extend_from_slice(&self.1.to_be_bytes());
         self.0
     }
+
+    // the record removing this version
+    fn deleted(self) -> Record {
+        let ts = self.1;
+        let mut record = Record::deleted(self.encode());
+        record.batch_state = BatchState::Txn(ts);
+
+        record
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -57,6 +72,7 @@ impl Transaction {
         let value = value.as_ref().to_vec();
 
         check_key_valid(&key)?;
+        check_key_size(&key, self.storage.opts.index_type)?;
         self.write(Record::normal(key, value))
     }
 
@@ -76,9 +92,7 @@ impl Transaction {
         let record = self.storage.get_record_with_pos(pos)?;
 
         match record.record_type {
-            crate::data::log_record::RecordType::Deleted => {
-                Err(anyhow::Error::msg("key not found!"))
-            }
+            crate::data::log_record::RecordType::Deleted => Err(Error::KeyNotFound),
             crate::data::log_record::RecordType::Normal => Ok(record.value().to_vec()),
         }
     }
@@ -92,21 +106,76 @@ impl Transaction {
     }
 
     pub fn commit(&self) -> Result<()> {
-        self.manager.remove_txn(self.ts);
+        // the mark tells a replay of the data files which versions were committed
+        let (_, commit_pos) = self.storage.commit_record(Record::txn_committed(self.ts))?;
+        self.storage
+            .reclaimable
+            .fetch_add(commit_pos.size as usize, Ordering::SeqCst);
+
+        // a later commit of the same keys waits for this one to be sent
+        let keys = self
+            .manager
+            .get_uncommitted_txn()
+            .get(&self.ts)
+            .cloned()
+            .unwrap_or_default();
+        let shards = self
+            .storage
+            .lock_shards(keys.iter().map(|key| key.as_slice()));
+        self.storage.publish(
+            || Ok(self.manager.remove_txn(self.ts)),
+            |keys| Some(self.change_event(keys.as_deref()?, commit_pos)),
+        )?;
+        drop(shards);
         self.manager.sync_to_file()?;
 
-        self.storage.sync()
+        self.storage.sync_by_policy()
     }
 
     pub fn rollback(&self) -> Result<()> {
         if let Some(keys) = self.manager.remove_txn(self.ts) {
             for key in keys {
-                self.storage.delete(KeySlice::new(key, self.ts).encode())?;
+                self.storage
+                    .txn_delete(KeySlice::new(key, self.ts).deleted())?;
             }
         }
 
         self.manager.sync_to_file()?;
-        self.storage.sync()
+        self.storage.sync_by_policy()
+    }
+
+    // the last write of the transaction to each key
+    fn change_event(&self, keys: &[Key], commit_pos: RecordPosition) -> ChangeEvent {
+        let mut seen = HashSet::new();
+        let changes = keys
+            .iter()
+            .filter(|key| seen.insert(*key))
+            .filter_map(|key| {
+                // a deleted version is gone from the index
+                let version = KeySlice::new(key.clone(), self.ts).encode();
+                let Some(pos) = self.storage.get_index(&version).get(&version) else {
+                    return Some(Change::Delete { key: key.clone() });
+                };
+
+                match self.storage.get_record_with_pos(pos) {
+                    Ok(record) => {
+                        let mut record = record.to_record();
+                        record.key = key.clone();
+                        Some(Change::from_record(&record))
+                    }
+                    Err(e) => {
+                        log::error!("read transaction {} change error: {}", self.ts, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        ChangeEvent {
+            changes,
+            txn_ts: Some(self.ts),
+            position: Some(ChangePosition::after(commit_pos)),
+        }
     }
 
     fn write(&self, record: Record) -> Result<()> {
@@ -119,6 +188,8 @@ impl Transaction {
                     self.manager.mark_to_clean(ts, key.clone())
                 }
             }
+            // first version of the key
+            Err(Error::KeyNotFound) => {}
             Err(e) => return Err(e),
         }
 
@@ -128,6 +199,7 @@ impl Transaction {
 
         let mut write_record = Record::normal(key_slice.encode(), value);
         write_record.record_type = record.record_type;
+        write_record.batch_state = BatchState::Txn(self.ts);
 
         self.storage.txn_write(write_record)
     }
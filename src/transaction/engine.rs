@@ -1,9 +1,12 @@
 use std::{sync::Arc, thread};
 
-use anyhow::Result;
 use crossbeam_channel::unbounded;
 
-use crate::{storage::Bitcask, transaction::KeySlice};
+use crate::{
+    error::{Error, Result},
+    storage::Bitcask,
+    transaction::KeySlice,
+};
 
 use super::{manager::TxnManager, Transaction};
 
@@ -13,7 +16,12 @@ pub struct TxnEngine {
 }
 
 impl TxnEngine {
-    pub fn new(storage: Bitcask) -> Result<Self> {
+    pub fn new(storage: Arc<Bitcask>) -> Result<Self> {
+        // the transaction file is rewritten on every commit
+        if storage.opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let (tx, rx) = unbounded();
 
         let manager = TxnManager::new(storage.opts.clone(), tx)?;
@@ -22,12 +30,11 @@ impl TxnEngine {
             // delete all former txn
             for (ts, keys) in manager.get_uncommitted_txn().drain() {
                 for key in keys {
-                    storage.delete(KeySlice::new(key, ts).encode())?;
+                    storage.txn_delete(KeySlice::new(key, ts).deleted())?;
                 }
             }
         }
 
-        let storage = Arc::new(storage);
         let manager = Arc::new(manager);
 
         let storage_ = storage.clone();
@@ -36,8 +43,8 @@ impl TxnEngine {
         thread::spawn(move || {
             while rx.recv().is_ok() {
                 for (ts, key) in manager_.pending_clean.lock().drain(..) {
-                    if let Err(e) = storage_.delete(KeySlice::new(key, ts).encode()) {
-                        log::error!("transaction clean up error!");
+                    if let Err(e) = storage_.txn_delete(KeySlice::new(key, ts).deleted()) {
+                        log::error!("transaction clean up error: {}", e);
                     }
                 }
             }
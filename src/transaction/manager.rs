@@ -1,14 +1,19 @@
 use std::{
     collections::{HashMap, HashSet},
     fs,
-    sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering},
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use anyhow::Result;
 use crossbeam_channel::Sender;
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 
-use crate::{consts::TXN_FILE, key::Key, options::BitcaskOptions};
+use crate::{
+    consts::TXN_FILE,
+    error::{Error, Result},
+    key::Key,
+    options::BitcaskOptions,
+};
 
 pub(crate) struct TxnManager {
     ts: AtomicU64,
@@ -23,10 +28,10 @@ impl TxnManager {
     pub(crate) fn new(ops: BitcaskOptions, signal: Sender<()>) -> Result<Self> {
         let txn_file_path = &ops.db_path.join(TXN_FILE);
 
-        let manager = match fs::read(&txn_file_path) {
+        let manager = match fs::read(txn_file_path) {
             Ok(buf) => {
                 let (active_txn, ts): (HashMap<u64, Vec<Key>>, u64) = bincode::deserialize(&buf)
-                    .map_err(|_| anyhow::Error::msg("new txn manager from txn file error!"))?;
+                    .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
 
                 TxnManager {
                     ts: AtomicU64::new(ts),
@@ -37,8 +42,7 @@ impl TxnManager {
                 }
             }
             Err(_) => {
-                fs::File::create(txn_file_path)
-                    .map_err(|_| anyhow::Error::msg("new txn file error!"))?;
+                fs::File::create(txn_file_path)?;
 
                 TxnManager {
                     ts: AtomicU64::new(0),
@@ -53,7 +57,7 @@ impl TxnManager {
         Ok(manager)
     }
 
-    pub(crate) fn get_uncommitted_txn(&self) -> MappedMutexGuard<HashMap<u64, Vec<Vec<u8>>>> {
+    pub(crate) fn get_uncommitted_txn(&self) -> MappedMutexGuard<'_, HashMap<u64, Vec<Vec<u8>>>> {
         MutexGuard::map(self.active_txn.lock(), |txn| txn)
     }
 
@@ -90,13 +94,20 @@ impl TxnManager {
     }
 
     pub(crate) fn sync_to_file(&self) -> Result<()> {
-        let bytes = bincode::serialize(&(&*self.active_txn.lock(), self.ts.load(Ordering::SeqCst)))
-            .unwrap();
+        // held until the rename, concurrent syncs do not share the temporary file
+        let active_txn = self.active_txn.lock();
+        let bytes = bincode::serialize(&(&*active_txn, self.ts.load(Ordering::SeqCst))).unwrap();
 
+        // write to a temporary file first, a backup copying it sees the old or the new content
         let path = self.storage_ops.db_path.join(TXN_FILE);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
 
-        std::fs::write(path, bytes)
-            .map_err(|_| anyhow::Error::msg("txn manager sync to file error!"))
+        Ok(())
     }
 
     pub(crate) fn mark_to_clean(&self, version: u64, key: Vec<u8>) {
@@ -1,6 +1,13 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::consts::DATA_FILE_SUFFIX;
+use crate::{
+    consts::{DATA_FILE_SUFFIX, DATA_HINT_FILE_SUFFIX},
+    error::Result,
+};
 
 pub fn get_merge_path(path: impl AsRef<Path>) -> PathBuf {
     path.as_ref().join(".merge")
@@ -10,3 +17,78 @@ pub fn get_data_file_path(path: impl AsRef<Path>, file_id: u32) -> PathBuf {
     path.as_ref()
         .join(format!("{:09}{}", file_id, DATA_FILE_SUFFIX))
 }
+
+// hint file of a sealed data file
+pub fn get_data_hint_file_path(path: impl AsRef<Path>, file_id: u32) -> PathBuf {
+    path.as_ref()
+        .join(format!("{:09}{}", file_id, DATA_HINT_FILE_SUFFIX))
+}
+
+// total size of all files under `path`
+pub fn dir_disk_size(path: impl AsRef<Path>) -> Result<usize> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            size += dir_disk_size(entry.path())?;
+        } else {
+            size += metadata.len() as usize;
+        }
+    }
+
+    Ok(size)
+}
+
+// unix timestamp in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+// a fresh database directory for a test, removed again when it goes out of scope.
+// declare it before the engine so the engine is closed first
+#[cfg(test)]
+pub(crate) struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        Self(path)
+    }
+
+    pub(crate) fn options(&self) -> crate::options::BitcaskOptions {
+        crate::options::BitcaskOptions {
+            db_path: self.0.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::OpenOptions,
+    io,
+    path::{Path, PathBuf},
+};
+
+use fs4::FileExt;
+
+use crate::{
+    consts::{FILE_LOCK, HINT_FILE_NAME, MERGE_FILE_NAME, TXN_FILE},
+    data::{
+        datafile::DataFile,
+        log_record::{BatchState, RecordPosition, RecordReader, RecordType},
+    },
+    error::{Error, Result},
+    key::Key,
+    merge::MergeMark,
+    options::IoType,
+    storage::Bitcask,
+    utils::{get_data_file_path, get_data_hint_file_path},
+};
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files: Vec<FileReport>,
+    pub issues: Vec<Issue>,
+}
+
+#[derive(Debug)]
+pub struct FileReport {
+    pub name: String,
+    pub size: u64,
+    // records that decoded with a valid crc32
+    pub records: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    // `len` bytes from `offset` hold no valid record
+    Corrupted { file: String, offset: u64, len: u64 },
+    // a write cut short at the end of the active file, truncated on the next open
+    TornTail { file: String, offset: u64, len: u64 },
+    // records of a batch whose finish mark was never written, discarded on open
+    UnfinishedBatch { seq: u64, records: u64 },
+    // a hint whose record is missing or holds another key
+    DanglingHint { file: String, offset: u64, key: Key },
+    BrokenMergeFile,
+    BrokenTxnFile,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            writeln!(
+                f,
+                "{}: {} records, {} bytes",
+                file.name, file.records, file.size
+            )?;
+        }
+
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+
+        match self.issues.len() {
+            0 => write!(f, "ok"),
+            n => write!(f, "{} issues found", n),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Corrupted { file, offset, len } => {
+                write!(f, "{}: {} corrupted bytes at offset {}", file, len, offset)
+            }
+            Issue::TornTail { file, offset, len } => {
+                write!(
+                    f,
+                    "{}: torn write of {} bytes at offset {}",
+                    file, len, offset
+                )
+            }
+            Issue::UnfinishedBatch { seq, records } => {
+                write!(f, "batch {}: {} records without finish mark", seq, records)
+            }
+            Issue::DanglingHint { file, offset, key } => write!(
+                f,
+                "{}: hint of key {:?} at offset {} points at no such record",
+                file,
+                String::from_utf8_lossy(key),
+                offset
+            ),
+            Issue::BrokenMergeFile => write!(f, "{}: broken merge mark", MERGE_FILE_NAME),
+            Issue::BrokenTxnFile => write!(f, "{}: broken transaction file", TXN_FILE),
+        }
+    }
+}
+
+// check every file of a closed database, it fails with `DatabaseLocked` while the database is open
+pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    let path = path.as_ref();
+
+    // read only, the files may be on read only media. no one holds a missing lock file
+    let lock_file = match OpenOptions::new().read(true).open(path.join(FILE_LOCK)) {
+        Ok(lock_file) => Some(lock_file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(lock_file) = &lock_file {
+        FileExt::try_lock_shared(lock_file).map_err(|_| Error::DatabaseLocked)?;
+    }
+
+    let mut verifier = Verifier {
+        path: path.to_path_buf(),
+        data_files: HashMap::new(),
+        report: VerifyReport::default(),
+    };
+
+    verifier.verify_data_files()?;
+    verifier.verify_hint_files()?;
+    verifier.verify_merge_file()?;
+    verifier.verify_txn_file()?;
+
+    Ok(verifier.report)
+}
+
+struct Verifier {
+    path: PathBuf,
+    data_files: HashMap<u32, DataFile>,
+    report: VerifyReport,
+}
+
+impl Verifier {
+    fn verify_data_files(&mut self) -> Result<()> {
+        let ids = Bitcask::load_data_file_ids(&self.path)?;
+
+        // a batch may be split by a file rotation
+        let mut batches: HashMap<u64, u64> = HashMap::new();
+        for (i, id) in ids.iter().enumerate() {
+            let file = DataFile::new_with_io_type(&self.path, *id, IoType::MemoryMap)?;
+            let name = file_name(get_data_file_path(&self.path, *id));
+
+            self.scan(&file, name, i == ids.len() - 1, |record, _| {
+                match record.batch_state {
+                    BatchState::Enable(seq) => *batches.entry(seq).or_default() += 1,
+                    BatchState::Finish(seq) => {
+                        batches.remove(&seq);
+                    }
+                    BatchState::Disable
+                    | BatchState::Txn(_)
+                    | BatchState::TxnCommit(_)
+                    | BatchState::Snapshot => {}
+                }
+            })?;
+
+            self.data_files.insert(*id, file);
+        }
+
+        let mut unfinished: Vec<_> = batches.into_iter().collect();
+        unfinished.sort_unstable();
+        for (seq, records) in unfinished {
+            self.report
+                .issues
+                .push(Issue::UnfinishedBatch { seq, records });
+        }
+
+        Ok(())
+    }
+
+    fn verify_hint_files(&mut self) -> Result<()> {
+        let mut hint_files = Vec::new();
+
+        let merge_hint_path = self.path.join(HINT_FILE_NAME);
+        if merge_hint_path.is_file() {
+            hint_files.push((DataFile::from_path(&merge_hint_path)?, merge_hint_path));
+        }
+
+        let mut ids: Vec<u32> = self.data_files.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let hint_path = get_data_hint_file_path(&self.path, id);
+            if hint_path.is_file() {
+                hint_files.push((DataFile::from_path(&hint_path)?, hint_path));
+            }
+        }
+
+        for (hint_file, hint_path) in hint_files {
+            let name = file_name(hint_path);
+
+            let mut hints = Vec::new();
+            self.scan(&hint_file, name.clone(), false, |hint, offset| {
+                // tombstones of a merge carry no position
+                if !(hint.record_type == RecordType::Deleted && hint.value().is_empty()) {
+                    hints.push((offset, hint.key().to_vec(), hint.value().to_vec()));
+                }
+            })?;
+
+            for (offset, key, value) in hints {
+                if !self.hint_matches(&key, &value) {
+                    self.report.issues.push(Issue::DanglingHint {
+                        file: name.clone(),
+                        offset,
+                        key,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn hint_matches(&self, key: &[u8], value: &[u8]) -> bool {
+        if value.len() != RecordPosition::new(0, 0, 0).encode().len() {
+            return false;
+        }
+
+        let pos = RecordPosition::decode(value);
+        self.data_files.get(&pos.file_id).is_some_and(|file| {
+            file.read_record_with_size(pos.offset, pos.size as u64)
+                .is_ok_and(|record| record.key() == key)
+        })
+    }
+
+    fn verify_merge_file(&mut self) -> Result<()> {
+        let merge_path = self.path.join(MERGE_FILE_NAME);
+        if !merge_path.is_file() {
+            return Ok(());
+        }
+
+        let merge_file = DataFile::from_path(&merge_path)?;
+        self.scan(&merge_file, file_name(merge_path), false, |_, _| {})?;
+
+        if MergeMark::read(&self.path).is_err() {
+            self.report.issues.push(Issue::BrokenMergeFile);
+        }
+
+        Ok(())
+    }
+
+    fn verify_txn_file(&mut self) -> Result<()> {
+        let txn_path = self.path.join(TXN_FILE);
+        if !txn_path.is_file() {
+            return Ok(());
+        }
+
+        let buf = std::fs::read(&txn_path)?;
+        self.report.files.push(FileReport {
+            name: TXN_FILE.to_string(),
+            size: buf.len() as u64,
+            records: 0,
+        });
+
+        // the same layout the transaction manager reads at startup
+        if bincode::deserialize::<(HashMap<u64, Vec<Key>>, u64)>(&buf).is_err() {
+            self.report.issues.push(Issue::BrokenTxnFile);
+        }
+
+        Ok(())
+    }
+
+    // call `f` on every valid record of `file` with its offset, bad spans go into the report
+    fn scan(
+        &mut self,
+        file: &DataFile,
+        name: String,
+        active: bool,
+        mut f: impl FnMut(&RecordReader, u64),
+    ) -> Result<()> {
+        let size = file.size()?;
+
+        let mut records = 0;
+        let mut offset = 0;
+        loop {
+            match file.read_record(offset) {
+                Ok(record) => {
+                    f(&record, offset);
+                    records += 1;
+                    offset += record.size() as u64;
+                }
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(Error::Corrupted { .. }) => {
+                    let next = file.next_valid_record(offset + 1)?;
+                    let len = next.unwrap_or(size) - offset;
+
+                    self.report.issues.push(match next {
+                        None if active => Issue::TornTail {
+                            file: name.clone(),
+                            offset,
+                            len,
+                        },
+                        _ => Issue::Corrupted {
+                            file: name.clone(),
+                            offset,
+                            len,
+                        },
+                    });
+
+                    match next {
+                        Some(next) => offset = next,
+                        None => break,
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.report.files.push(FileReport {
+            name,
+            size,
+            records,
+        });
+
+        Ok(())
+    }
+}
+
+fn file_name(path: PathBuf) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::fs::FileExt, time::Duration};
+
+    use crate::{
+        data::log_record::Record,
+        error::Result,
+        options::{BitcaskOptions, WriteBatchOptions},
+        storage::Bitcask,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let path = "/tmp/bitcask_verify";
+        let _ = std::fs::remove_dir_all(path);
+        let ops = BitcaskOptions {
+            db_path: path.into(),
+            max_file_size: 4 << 10,
+            ..Default::default()
+        };
+
+        let (pos, active_id) = {
+            let bitcask = Bitcask::open(ops.clone())?;
+            for i in 0..300 {
+                bitcask.put(format!("{:04}", i), format!("{:032}", i))?;
+            }
+            bitcask.merge()?;
+            for i in 0..300 {
+                bitcask.put_with_ttl(format!("{:04}", i), "x", Duration::from_secs(60))?;
+            }
+            let mut batch = bitcask.new_batch_write(WriteBatchOptions::default())?;
+            batch.put("batch", "x")?;
+            batch.commit()?;
+
+            // the database is locked while it is open
+            assert!(matches!(verify(path), Err(Error::DatabaseLocked)));
+
+            let pos = bitcask.get_index(b"0010").get(b"0010").unwrap();
+            let active_id = bitcask.active_file.read().id;
+            bitcask.close()?;
+            (pos, active_id)
+        };
+
+        let report = verify(path)?;
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.files.iter().any(|file| file.name == HINT_FILE_NAME));
+        assert!(report.files.iter().any(|file| file.name == MERGE_FILE_NAME));
+
+        // a copy without the lock file is not locked, verify leaves it without one
+        std::fs::remove_file(Path::new(path).join(FILE_LOCK))?;
+        assert!(verify(path)?.is_ok());
+        assert!(!Path::new(path).join(FILE_LOCK).exists());
+
+        // a flipped byte in a sealed file, a torn tail and a batch cut short in the active file
+        let file = OpenOptions::new()
+            .write(true)
+            .open(get_data_file_path(path, pos.file_id))?;
+        file.write_at(&[0xff], pos.offset + pos.size as u64 - 5)?;
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(get_data_file_path(path, active_id))?;
+        let mut record = Record::normal("unfinished".into(), "x".into());
+        record.enable_batch(100);
+        file.write_all(&record.encode())?;
+        let torn = Record::normal("torn".into(), "x".into()).encode();
+        file.write_all(&torn[..torn.len() - 1])?;
+
+        let report = verify(path)?;
+        let data_name = |id| file_name(get_data_file_path(path, id));
+        let hint_name = file_name(get_data_hint_file_path(path, pos.file_id));
+        assert_eq!(
+            vec![
+                Issue::Corrupted {
+                    file: data_name(pos.file_id),
+                    offset: pos.offset,
+                    len: pos.size as u64,
+                },
+                Issue::TornTail {
+                    file: data_name(active_id),
+                    offset: std::fs::metadata(get_data_file_path(path, active_id))?.len()
+                        - torn.len() as u64
+                        + 1,
+                    len: torn.len() as u64 - 1,
+                },
+                Issue::UnfinishedBatch {
+                    seq: 100,
+                    records: 1
+                },
+            ],
+            report.issues[..3]
+        );
+        assert!(matches!(
+            &report.issues[3..],
+            [Issue::DanglingHint { file, key, .. }] if *file == hint_name && key == b"0010"
+        ));
+
+        Ok(())
+    }
+}